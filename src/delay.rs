@@ -1,7 +1,7 @@
 use crate::dsp::StereoFrame;
 use crate::saturation::{Saturater, SaturationMode};
 use std::sync::mpsc::Receiver;
-use crate::filters::LPFilter;
+use crate::filters::{Filter, FilterMode, BUTTERWORTH_Q};
 
 #[derive(Debug)]
 pub struct DelayLine {
@@ -65,8 +65,8 @@ pub struct StereoDelay {
     dl_right: DelayLine,
     sat_l: Saturater,
     sat_r: Saturater,
-    filter_l: LPFilter,
-    filter_r: LPFilter,
+    filter_l: Filter,
+    filter_r: Filter,
     sr: usize,
     params: DelayParams,
     params_receiver: Receiver<DelayParams>,
@@ -87,12 +87,12 @@ impl StereoDelay {
         let time_samples_l = (time_l * sr as f32) as usize;
         let time_samples_r = (time_r * sr as f32) as usize;
         Self {
-            dl_left: DelayLine::new(time_samples_l, 44000 * 6),
-            dl_right: DelayLine::new(time_samples_r, 44000 * 6),
+            dl_left: DelayLine::new(time_samples_l, sr * 6),
+            dl_right: DelayLine::new(time_samples_r, sr * 6),
             sat_l: Saturater::new(0.7, SaturationMode::Tape),
             sat_r: Saturater::new(0.7, SaturationMode::Tape),
-            filter_l: LPFilter::new(44000, 10000.0),
-            filter_r: LPFilter::new(44000, 10000.0),
+            filter_l: Filter::new(sr, FilterMode::Lowpass, 10000.0, BUTTERWORTH_Q),
+            filter_r: Filter::new(sr, FilterMode::Lowpass, 10000.0, BUTTERWORTH_Q),
             sr,
             params: DelayParams {
                 feedback,
@@ -130,8 +130,8 @@ impl StereoDelay {
             }
             // Reduce recomputation when not necessary
             if self.feedback_params.cutoff_freq != params.cutoff_freq {
-                self.filter_l.compute_coeffs(params.cutoff_freq);
-                self.filter_r.compute_coeffs(params.cutoff_freq);
+                self.filter_l.compute_coeffs(params.cutoff_freq, BUTTERWORTH_Q);
+                self.filter_r.compute_coeffs(params.cutoff_freq, BUTTERWORTH_Q);
             }
             self.feedback_params = params;
         }
@@ -1,5 +1,9 @@
+use std::f32::consts::{FRAC_1_SQRT_2, FRAC_PI_2};
 use std::ops::AddAssign;
 
+/// The rate everything runs at internally, source audio is converted to this on load
+pub const SAMPLE_RATE: u32 = 44000;
+
 #[derive(Debug, Clone, Copy)]
 pub struct StereoFrame(pub f32, pub f32);
 
@@ -8,6 +12,7 @@ impl StereoFrame {
         Self(sample, sample)
     }
 
+    #[allow(dead_code)]
     pub fn mono(&self) -> f32 {
         (self.1 + self.0) / 2.0
     }
@@ -27,3 +32,148 @@ impl AddAssign for StereoFrame {
 pub fn interleave(buf: &[StereoFrame]) -> Vec<f32> {
     buf.iter().flat_map(|frame| [frame.0, frame.1]).collect()
 }
+
+// Left and right gains for the common surround layouts, in WAV channel order.
+// Centre goes to both sides at -3dB, surrounds to their own side at -3dB and LFE is dropped.
+const LEFT: (f32, f32) = (1.0, 0.0);
+const RIGHT: (f32, f32) = (0.0, 1.0);
+const CENTRE: (f32, f32) = (FRAC_1_SQRT_2, FRAC_1_SQRT_2);
+const LFE: (f32, f32) = (0.0, 0.0);
+const SURROUND_LEFT: (f32, f32) = (FRAC_1_SQRT_2, 0.0);
+const SURROUND_RIGHT: (f32, f32) = (0.0, FRAC_1_SQRT_2);
+
+fn downmix_gains(channels: u16) -> Option<&'static [(f32, f32)]> {
+    match channels {
+        3 => Some(&[LEFT, RIGHT, CENTRE]),
+        4 => Some(&[LEFT, RIGHT, SURROUND_LEFT, SURROUND_RIGHT]),
+        5 => Some(&[LEFT, RIGHT, CENTRE, SURROUND_LEFT, SURROUND_RIGHT]),
+        6 => Some(&[LEFT, RIGHT, CENTRE, LFE, SURROUND_LEFT, SURROUND_RIGHT]),
+        _ => None,
+    }
+}
+
+/// Splits interleaved samples into stereo frames.
+/// Mono is copied to both sides, and surround layouts up to 5.1 are downmixed,
+/// scaled so a full scale signal on every channel can't clip.
+/// Anything wider is folded, with even channels averaged into the left and odd into the right.
+pub fn deinterleave(samples: &[f32], channels: u16) -> Vec<StereoFrame> {
+    match channels {
+        0 => vec![],
+        1 => samples.iter().copied().map(StereoFrame::new).collect(),
+        2 => samples
+            .chunks_exact(2)
+            .map(|frame| StereoFrame(frame[0], frame[1]))
+            .collect(),
+        n => match downmix_gains(n) {
+            Some(gains) => {
+                let total: f32 = gains.iter().map(|(left, _)| left).sum();
+                samples
+                    .chunks_exact(n as usize)
+                    .map(|frame| {
+                        let mut mixed = StereoFrame(0.0, 0.0);
+                        for (sample, (left, right)) in frame.iter().zip(gains) {
+                            mixed += StereoFrame(sample * left, sample * right);
+                        }
+                        StereoFrame(mixed.0 / total, mixed.1 / total)
+                    })
+                    .collect()
+            }
+            None => samples
+                .chunks_exact(n as usize)
+                .map(|frame| {
+                    let side = |offset: usize| {
+                        let channels = frame.iter().skip(offset).step_by(2);
+                        channels.clone().sum::<f32>() / channels.count() as f32
+                    };
+                    StereoFrame(side(0), side(1))
+                })
+                .collect(),
+        },
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PanLaw {
+    Linear,        // -6dB in the centre
+    ConstantPower, // -3dB in the centre
+    Compromise,    // -4.5dB in the centre, between the other two
+}
+
+impl PanLaw {
+    /// Left and right gains for a mono source, with pan from -1 to 1
+    pub fn gains(&self, pan: f32) -> (f32, f32) {
+        let x = (pan.clamp(-1.0, 1.0) + 1.0) / 2.0;
+        let linear = (1.0 - x, x);
+        let power = ((x * FRAC_PI_2).cos(), (x * FRAC_PI_2).sin());
+        match self {
+            PanLaw::Linear => linear,
+            PanLaw::ConstantPower => power,
+            PanLaw::Compromise => ((linear.0 * power.0).sqrt(), (linear.1 * power.1).sqrt()),
+        }
+    }
+
+    /// Gains for a stereo source, which only turn down the opposite side.
+    /// The centre is left at unity so panning doesn't collapse the stereo image.
+    pub fn balance(&self, pan: f32) -> (f32, f32) {
+        let (left, right) = self.gains(pan);
+        let (centre, _) = self.gains(0.0);
+        ((left / centre).min(1.0), (right / centre).min(1.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    Cubic,
+}
+
+/// Read a frame at a fractional position, wrapping around the buffer
+pub fn read_interpolated(buf: &[StereoFrame], pos: f64, mode: Interpolation) -> StereoFrame {
+    let len = buf.len() as i64;
+    let index = pos.floor() as i64;
+    let t = (pos - pos.floor()) as f32;
+    let frame = |offset: i64| buf[(index + offset).rem_euclid(len) as usize];
+
+    match mode {
+        Interpolation::Linear => {
+            let (first, second) = (frame(0), frame(1));
+            StereoFrame(
+                (1.0 - t) * first.0 + t * second.0,
+                (1.0 - t) * first.1 + t * second.1,
+            )
+        }
+        Interpolation::Cubic => {
+            let (x0, x1, x2, x3) = (frame(-1), frame(0), frame(1), frame(2));
+            StereoFrame(
+                hermite(x0.0, x1.0, x2.0, x3.0, t),
+                hermite(x0.1, x1.1, x2.1, x3.1, t),
+            )
+        }
+    }
+}
+
+// Catmull-Rom spline through four points, evaluated between x1 and x2
+fn hermite(x0: f32, x1: f32, x2: f32, x3: f32, t: f32) -> f32 {
+    let c1 = 0.5 * (x2 - x0);
+    let c2 = x0 - 2.5 * x1 + 2.0 * x2 - 0.5 * x3;
+    let c3 = 0.5 * (x3 - x0) + 1.5 * (x1 - x2);
+    ((c3 * t + c2) * t + c1) * t + x1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surround_downmixes_to_its_sides() {
+        // Left, right, centre, LFE, surround left and surround right
+        let frame = [1.0, 0.0, 0.5, 1.0, 0.0, 0.25];
+        let mixed = deinterleave(&frame, 6);
+        let total = 1.0 + 2.0 * FRAC_1_SQRT_2;
+        let left = (1.0 + 0.5 * FRAC_1_SQRT_2) / total;
+        let right = (0.5 + 0.25) * FRAC_1_SQRT_2 / total;
+        assert_eq!(mixed.len(), 1);
+        assert!((mixed[0].0 - left).abs() < 1e-6);
+        assert!((mixed[0].1 - right).abs() < 1e-6);
+    }
+}
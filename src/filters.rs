@@ -1,67 +1,76 @@
 use std::f32::consts::PI;
 
-#[derive(Debug)]
-pub struct LPFilter {
-    a0: f32,
+/// Q of a Butterworth response, the flattest passband without resonance
+pub const BUTTERWORTH_Q: f32 = 0.707;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterMode {
+    Lowpass,
+    Highpass,
+    Bandpass,
+}
+
+impl FilterMode {
+    pub const ALL: [FilterMode; 3] = [
+        FilterMode::Lowpass,
+        FilterMode::Highpass,
+        FilterMode::Bandpass,
+    ];
+}
+
+/// Trapezoidal state-variable filter, which stays stable while the cutoff moves
+#[derive(Debug, Clone, Copy)]
+pub struct Filter {
+    mode: FilterMode,
+    k: f32, // Damping, 1 / Q
     a1: f32,
     a2: f32,
-    b0: f32,
-    b1: f32,
-    b2: f32,
+    a3: f32,
     sr: usize,
-    y: f32,
-    x: f32,
-    y1: f32,
-    y2: f32,
-    x1: f32,
-    x2: f32,
+    ic1eq: f32, // Integrator states
+    ic2eq: f32,
 }
 
-impl LPFilter {
-    pub fn new(sr: usize, cutoff: f32) -> Self {
+impl Filter {
+    pub fn new(sr: usize, mode: FilterMode, cutoff: f32, q: f32) -> Self {
         let mut this = Self {
-            a0: 0.0,
+            mode,
+            k: 0.0,
             a1: 0.0,
             a2: 0.0,
-            b0: 0.0,
-            b1: 0.0,
-            b2: 0.0,
+            a3: 0.0,
             sr,
-            y: 0.0,
-            x: 0.0,
-            y1: 0.0,
-            y2: 0.0,
-            x1: 0.0,
-            x2: 0.0,
+            ic1eq: 0.0,
+            ic2eq: 0.0,
         };
 
-        this.compute_coeffs(cutoff);
+        this.compute_coeffs(cutoff, q);
 
         this
     }
 
-    pub fn compute_coeffs(&mut self, cutoff: f32) {
-        let w = 2.0 * PI * cutoff / self.sr as f32;
-        let alpha = w.sin() / 1.414; // Butterworth filter Q value, somewhat arbitrary
-        let cw = w.cos();
+    pub fn compute_coeffs(&mut self, cutoff: f32, q: f32) {
+        // Kept below Nyquist, where the prewarping blows up
+        let cutoff = cutoff.clamp(1.0, 0.49 * self.sr as f32);
+        let g = (PI * cutoff / self.sr as f32).tan();
+        self.k = q.max(0.1).recip();
 
-        self.a0 = 1.0 + alpha;
-        self.a1 = -2.0 * cw;
-        self.a2 = 1.0 - alpha;
-        self.b0 = (1.0 - cw) / 2.0;
-        self.b1 = 1.0 - cw;
-        self.b2 = (1.0 - cw) / 2.0;
+        self.a1 = 1.0 / (1.0 + g * (g + self.k));
+        self.a2 = g * self.a1;
+        self.a3 = g * self.a2;
     }
 
     pub fn process(&mut self, sample: f32) -> f32 {
-        self.x2 = self.x1;
-        self.x1 = self.x;
-        self.x = sample;
-        self.y2 = self.y1;
-        self.y1 = self.y;
-
-        self.y = (self.b0 * self.x + self.b1 * self.x1 + self.b2 * self.x2 - self.a1 * self.y1 - self.a2 * self.y2) / self.a0;
+        let v3 = sample - self.ic2eq;
+        let v1 = self.a1 * self.ic1eq + self.a2 * v3;
+        let v2 = self.ic2eq + self.a2 * self.ic1eq + self.a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
 
-        self.y
+        match self.mode {
+            FilterMode::Lowpass => v2,
+            FilterMode::Highpass => sample - self.k * v1 - v2,
+            FilterMode::Bandpass => v1,
+        }
     }
-}
\ No newline at end of file
+}
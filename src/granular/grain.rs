@@ -1,10 +1,97 @@
-use crate::dsp::StereoFrame;
+use crate::dsp::{read_interpolated, Interpolation, StereoFrame, SAMPLE_RATE};
+use crate::filters::Filter;
+use rand::Rng;
 use std::f32::consts::PI;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum EnvelopeMode {
     Smooth,
     Exp,
+    Gaussian,
+    Tukey,
+    Trapezoid,
+    Blackman,
+    Sinc,
+    Custom, // Drawn as breakpoints in the Ui
+}
+
+impl EnvelopeMode {
+    pub const ALL: [EnvelopeMode; 8] = [
+        EnvelopeMode::Smooth,
+        EnvelopeMode::Exp,
+        EnvelopeMode::Gaussian,
+        EnvelopeMode::Tukey,
+        EnvelopeMode::Trapezoid,
+        EnvelopeMode::Blackman,
+        EnvelopeMode::Sinc,
+        EnvelopeMode::Custom,
+    ];
+
+    /// What the shape parameter controls for this window, if it has one
+    pub fn shape_name(&self) -> Option<&'static str> {
+        match self {
+            EnvelopeMode::Smooth | EnvelopeMode::Custom => None,
+            EnvelopeMode::Exp => Some("Shape"),
+            EnvelopeMode::Gaussian => Some("Width"),
+            EnvelopeMode::Tukey => Some("Taper"),
+            EnvelopeMode::Trapezoid => Some("Ramp"),
+            EnvelopeMode::Blackman => Some("Alpha"),
+            EnvelopeMode::Sinc => Some("Lobes"),
+        }
+    }
+}
+
+/// A point on a drawn envelope
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Breakpoint {
+    pub t: f32,     // Position through the grain, from 0 to 1
+    pub level: f32, // From 0 to 1
+    /// Curvature of the segment leading into this point, 0 is a straight line
+    pub tension: f32,
+}
+
+impl Breakpoint {
+    pub fn new(t: f32, level: f32, tension: f32) -> Self {
+        Self { t, level, tension }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Forward,
+    Reverse,
+    PingPong,
+    Random, // Forward or reverse, chosen per grain
+}
+
+impl Direction {
+    /// Picks a concrete direction for a newly spawned grain
+    pub fn resolve(self, rng: &mut impl Rng) -> Self {
+        match self {
+            Direction::Random if rng.random() => Direction::Reverse,
+            Direction::Random => Direction::Forward,
+            direction => direction,
+        }
+    }
+}
+
+/// Everything a grain needs at spawn time, resolved by the engine
+#[derive(Debug, Clone, Copy)]
+pub struct GrainSettings {
+    pub length: usize,
+    pub start: usize,
+    pub slot: usize, // The sample slot the grain reads from
+    /// Read offset to begin from in source samples, for grains due between samples
+    pub phase: f64,
+    /// Left and right gains, from the pan law
+    pub pan_gains: (f32, f32),
+    pub gain: f32,
+    /// Playback speed relative to the source, combining rate and pitch
+    pub speed: f32,
+    pub direction: Direction,
+    pub interpolation: Interpolation,
+    /// Copied for each channel, so both start from silence
+    pub filter: Option<Filter>,
 }
 
 #[derive(Debug)]
@@ -12,48 +99,46 @@ pub struct Grain {
     t: usize,
     length: usize,
     start: usize,
-    pan: f32,
-    /// The number of grains that were active when spawned
-    scale: u16,
+    slot: usize,
+    phase: f64, // Fractional read offset from the start, in source samples
+    speed: f32,
+    direction: Direction,
+    pan_gains: (f32, f32),
+    gain: f32,
     pub finished: bool,
-    envelope_mode: EnvelopeMode,
-    envelope_sharpness: f32,
-    envelope_shape: f32,
+    interpolation: Interpolation,
+    filters: Option<[Filter; 2]>, // Left and right
 }
 
 impl Grain {
-    pub fn new(
-        length: usize,
-        start: usize,
-        pan: f32,
-        scale: u16,
-        envelope_mode: EnvelopeMode,
-        envelope_sharpness: f32,
-        envelope_shape: f32,
-    ) -> Self {
+    pub fn new(settings: GrainSettings) -> Self {
         Self {
             t: 0,
-            length,
-            start,
-            pan,
+            length: settings.length,
+            start: settings.start,
+            slot: settings.slot,
+            phase: settings.phase,
+            speed: settings.speed,
+            direction: settings.direction,
+            pan_gains: settings.pan_gains,
+            gain: settings.gain,
             finished: false,
-            scale,
-            envelope_mode,
-            envelope_sharpness,
-            envelope_shape,
+            interpolation: settings.interpolation,
+            filters: settings.filter.map(|filter| [filter, filter]),
         }
     }
 
-    pub fn env(&self) -> f32 {
-        return match self.envelope_mode {
-            EnvelopeMode::Smooth => window(self.length, self.t),
-            EnvelopeMode::Exp => exp(
-                self.t as f32 / self.length as f32,
-                self.envelope_shape,
-                8.0 * (self.envelope_sharpness - 1.0),
-                8.0 * (self.envelope_sharpness - 1.0),
-            ),
-        };
+    pub fn env(&self, envelope: &EnvelopeTable) -> f32 {
+        envelope.read(self.t as f32 / self.length as f32)
+    }
+
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    /// Number of samples the grain has played for
+    pub fn age(&self) -> usize {
+        self.t
     }
 
     pub fn finished() -> Self {
@@ -63,20 +148,41 @@ impl Grain {
         }
     }
 
-    pub fn read(&mut self, buffer: &[StereoFrame]) -> StereoFrame {
-        let read_pos = self.start + self.t;
-        let out = buffer[read_pos % buffer.len()];
+    /// Read offset from the start, following the direction of playback
+    fn offset(&self) -> f64 {
+        // How far through the source the grain travels over its lifetime
+        let span = self.length as f64 * self.speed as f64;
+        match self.direction {
+            Direction::Forward | Direction::Random => self.phase,
+            Direction::Reverse => span - self.phase,
+            Direction::PingPong => self.phase.min(span - self.phase),
+        }
+    }
 
-        self.t += 2;
+    /// The current output of the grain from a buffer, without moving it forward
+    pub fn frame(&self, buffer: &[StereoFrame], envelope: &EnvelopeTable) -> StereoFrame {
+        let read_pos = self.start as f64 + self.offset();
+        let out = read_interpolated(buffer, read_pos, self.interpolation);
+
+        let envelope_val = self.env(envelope);
+        let windowed = out.scale(envelope_val * self.gain);
+        StereoFrame(windowed.0 * self.pan_gains.0, windowed.1 * self.pan_gains.1)
+    }
+
+    /// Runs the grain's output through its own filter, if it has one
+    pub fn filter(&mut self, frame: StereoFrame) -> StereoFrame {
+        match &mut self.filters {
+            Some([left, right]) => StereoFrame(left.process(frame.0), right.process(frame.1)),
+            None => frame,
+        }
+    }
+
+    pub fn advance(&mut self) {
+        self.t += 1;
+        self.phase += self.speed as f64;
         if self.t >= self.length {
             self.finished = true;
         };
-        let envelope_val = self.env();
-        let windowed = out.scale((self.scale as f32).recip() * envelope_val);
-        StereoFrame(
-            (1.0 - self.pan) * windowed.0 * 0.5,
-            (1.0 + self.pan) * windowed.1 * 0.5,
-        )
     }
 }
 
@@ -84,18 +190,124 @@ impl Default for Grain {
     fn default() -> Self {
         Self {
             t: 0,
-            length: 44000,
+            length: SAMPLE_RATE as usize,
             start: 0,
-            pan: 0.0,
+            slot: 0,
+            phase: 0.0,
+            speed: 1.0,
+            direction: Direction::Forward,
+            pan_gains: (1.0, 1.0),
+            gain: 1.0,
             finished: false,
-            scale: 1,
-            envelope_mode: EnvelopeMode::Smooth,
-            envelope_sharpness: 0.0,
-            envelope_shape: 0.5,
+            interpolation: Interpolation::Linear,
+            filters: None,
         }
     }
 }
 
+const TABLE_SIZE: usize = 1024;
+
+/// A grain window sampled into a table, rebuilt only when the envelope settings change,
+/// so grains just interpolate the table each sample.
+/// Grains that are already playing pick up the new shape.
+#[derive(Debug, Clone)]
+pub struct EnvelopeTable {
+    values: [f32; TABLE_SIZE + 1], // The extra point is the end of the grain
+    mode: EnvelopeMode,
+    sharpness: f32,
+    shape: f32,
+    points: Vec<Breakpoint>, // Reserved up front, so updates don't allocate
+}
+
+impl EnvelopeTable {
+    /// Most breakpoints a custom envelope can have
+    pub const MAX_POINTS: usize = 32;
+
+    pub fn new(mode: EnvelopeMode, sharpness: f32, shape: f32, points: &[Breakpoint]) -> Self {
+        let mut table = Self {
+            values: [0.0; TABLE_SIZE + 1],
+            mode,
+            sharpness,
+            shape,
+            points: Vec::with_capacity(Self::MAX_POINTS),
+        };
+        table.set_points(points);
+        table.fill();
+        table
+    }
+
+    /// Rebuilds the table if any of the settings differ from the current ones
+    pub fn update(
+        &mut self,
+        mode: EnvelopeMode,
+        sharpness: f32,
+        shape: f32,
+        points: &[Breakpoint],
+    ) {
+        let points = &points[..points.len().min(Self::MAX_POINTS)];
+        if (mode, sharpness, shape) != (self.mode, self.sharpness, self.shape)
+            || points != self.points.as_slice()
+        {
+            self.mode = mode;
+            self.sharpness = sharpness;
+            self.shape = shape;
+            self.set_points(points);
+            self.fill();
+        }
+    }
+
+    fn set_points(&mut self, points: &[Breakpoint]) {
+        self.points.clear();
+        self.points
+            .extend_from_slice(&points[..points.len().min(Self::MAX_POINTS)]);
+    }
+
+    fn fill(&mut self) {
+        for (i, value) in self.values.iter_mut().enumerate() {
+            *value = envelope(
+                self.mode,
+                self.sharpness,
+                self.shape,
+                &self.points,
+                i as f32 / TABLE_SIZE as f32,
+            );
+        }
+    }
+
+    /// Envelope level at a point through the grain, from 0 to 1
+    pub fn read(&self, t: f32) -> f32 {
+        let pos = t.clamp(0.0, 1.0) * TABLE_SIZE as f32;
+        let index = (pos as usize).min(TABLE_SIZE - 1);
+        let frac = pos - index as f32;
+        (1.0 - frac) * self.values[index] + frac * self.values[index + 1]
+    }
+}
+
+/// Evaluates a window directly, with t from 0 to 1
+pub fn envelope(
+    mode: EnvelopeMode,
+    sharpness: f32,
+    shape: f32,
+    points: &[Breakpoint],
+    t: f32,
+) -> f32 {
+    match mode {
+        EnvelopeMode::Smooth => window(TABLE_SIZE, (t * TABLE_SIZE as f32) as usize),
+        EnvelopeMode::Exp => exp(t, shape, 8.0 * (sharpness - 1.0), 8.0 * (sharpness - 1.0)),
+        EnvelopeMode::Gaussian => gaussian(t, 0.05 + 0.45 * shape),
+        EnvelopeMode::Tukey => tukey(t, shape),
+        EnvelopeMode::Trapezoid => trapezoid(t, 0.5 * shape),
+        EnvelopeMode::Blackman => blackman(t, 0.32 * shape),
+        EnvelopeMode::Sinc => sinc(t, 1.0 + 8.0 * shape),
+        EnvelopeMode::Custom => breakpoints(t, points),
+    }
+}
+
+/// Converts a transposition in semitones to a playback speed multiplier
+pub fn semitones_to_ratio(semitones: f32) -> f32 {
+    (semitones / 12.0).exp2()
+}
+
 pub fn window(n: usize, t: usize) -> f32 {
     0.5 - 0.5 * (2.0 * PI * t as f32 / n as f32).cos()
 }
@@ -119,3 +331,83 @@ pub fn exp(t: f32, m: f32, c1: f32, c2: f32) -> f32 {
         1.0 - ((-(c2 * (t - m) / (1.0 - m))).exp() - 1.0) / ((-c2).exp() - 1.0)
     }
 }
+
+// Exponential curve from 0 to 1, using the same shaping as `exp`
+pub fn bend(x: f32, c: f32) -> f32 {
+    if c.abs() <= 0.01 {
+        x
+    } else {
+        ((c * x).exp() - 1.0) / (c.exp() - 1.0)
+    }
+}
+
+// Curve through breakpoints sorted by time, holding the end levels outside of them
+pub fn breakpoints(t: f32, points: &[Breakpoint]) -> f32 {
+    let Some(first) = points.first() else {
+        return 0.0;
+    };
+    if t <= first.t {
+        return first.level;
+    }
+    for pair in points.windows(2) {
+        let (from, to) = (pair[0], pair[1]);
+        if t <= to.t {
+            let x = (t - from.t) / (to.t - from.t).max(f32::EPSILON);
+            return from.level + (to.level - from.level) * bend(x, to.tension);
+        }
+    }
+    points[points.len() - 1].level
+}
+
+// Bell curve with standard deviation as a fraction of the grain, shifted and rescaled so the
+// edges reach zero even at wide settings
+pub fn gaussian(t: f32, sigma: f32) -> f32 {
+    let bell = |t: f32| (-0.5 * ((t - 0.5) / sigma).powi(2)).exp();
+    let edge = bell(0.0);
+    ((bell(t) - edge) / (1.0 - edge)).max(0.0)
+}
+
+// Flat top with cosine tapers, alpha is the tapered fraction, from rectangular at 0 to Hann at 1
+pub fn tukey(t: f32, alpha: f32) -> f32 {
+    let edge = t.min(1.0 - t);
+    if alpha <= 0.0 || edge >= alpha / 2.0 {
+        1.0
+    } else {
+        0.5 - 0.5 * (2.0 * PI * edge / alpha).cos()
+    }
+}
+
+// Flat top with linear ramps, each taking a fraction of the grain up to half
+pub fn trapezoid(t: f32, ramp: f32) -> f32 {
+    if ramp <= 0.0 {
+        1.0
+    } else {
+        (t.min(1.0 - t) / ramp).min(1.0)
+    }
+}
+
+// Generalised Blackman window, alpha of 0.16 is the classic shape and 0 is Hann
+pub fn blackman(t: f32, alpha: f32) -> f32 {
+    let a0 = (1.0 - alpha) / 2.0;
+    let a2 = alpha / 2.0;
+    a0 - 0.5 * (2.0 * PI * t).cos() + a2 * (4.0 * PI * t).cos()
+}
+
+// Hann windowed sinc, with the number of zero crossings each side of the centre
+pub fn sinc(t: f32, lobes: f32) -> f32 {
+    let x = PI * lobes * (2.0 * t - 1.0);
+    let sinc = if x.abs() < 1e-6 { 1.0 } else { x.sin() / x };
+    sinc * (0.5 - 0.5 * (2.0 * PI * t).cos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaussian_reaches_zero_at_the_edges() {
+        assert_eq!(gaussian(0.0, 0.5), 0.0);
+        assert_eq!(gaussian(1.0, 0.5), 0.0);
+        assert_eq!(gaussian(0.5, 0.5), 1.0);
+    }
+}
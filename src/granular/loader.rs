@@ -0,0 +1,164 @@
+use crate::dsp::{deinterleave, StereoFrame, SAMPLE_RATE};
+use crate::granular::onset;
+use crate::granular::synth::{self, SynthParams};
+use crate::granular::GranularParams;
+use crate::resample::resample;
+use rodio::decoder::DecoderError;
+use rodio::{Decoder, Source};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+
+/// Audio decoded from a file, converted to stereo at the engine sample rate
+#[derive(Debug)]
+pub struct LoadedSample {
+    pub frames: Vec<StereoFrame>,
+    pub sample_rate: u32, // The rate of the file before conversion
+    pub channels: u16,
+    pub onsets: Vec<usize>, // Transients, in samples
+}
+
+impl LoadedSample {
+    /// No audio, for clearing a slot
+    pub fn empty() -> Self {
+        Self {
+            frames: vec![],
+            sample_rate: SAMPLE_RATE,
+            channels: 1,
+            onsets: vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(PathBuf, std::io::Error),
+    Unsupported(PathBuf, DecoderError),
+    Empty(PathBuf),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Io(path, e) => write!(f, "Couldn't open {}: {e}", path.display()),
+            LoadError::Unsupported(path, e) => {
+                write!(f, "Couldn't decode {}: {e}", path.display())
+            }
+            LoadError::Empty(path) => write!(f, "{} contains no audio", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Decodes any supported format (WAV, FLAC, OGG, MP3 or AIFF)
+pub fn load(path: &Path) -> Result<LoadedSample, LoadError> {
+    let file = File::open(path).map_err(|e| LoadError::Io(path.to_path_buf(), e))?;
+    let decoder = Decoder::new(BufReader::new(file))
+        .map_err(|e| LoadError::Unsupported(path.to_path_buf(), e))?;
+
+    let sample_rate = decoder.sample_rate();
+    let channels = decoder.channels();
+    let interleaved: Vec<f32> = decoder.convert_samples().collect();
+    if interleaved.is_empty() || channels == 0 {
+        return Err(LoadError::Empty(path.to_path_buf()));
+    }
+
+    let frames = resample(
+        &deinterleave(&interleaved, channels),
+        sample_rate,
+        SAMPLE_RATE,
+    );
+    Ok(LoadedSample {
+        onsets: onset::detect(&frames),
+        frames,
+        sample_rate,
+        channels,
+    })
+}
+
+/// Where a slot's audio comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum SlotSource {
+    File(PathBuf), // An empty path leaves the slot silent
+    Synth(SynthParams),
+}
+
+impl SlotSource {
+    pub fn is_empty(&self) -> bool {
+        matches!(self, SlotSource::File(path) if path.as_os_str().is_empty())
+    }
+}
+
+/// Decodes a file, or runs a generator
+pub fn load_source(source: &SlotSource) -> Result<LoadedSample, LoadError> {
+    match source {
+        SlotSource::File(path) => load(path),
+        SlotSource::Synth(params) => synth::generate(params),
+    }
+}
+
+/// Messages handled by the loader thread
+#[derive(Debug)]
+pub enum LoaderMessage {
+    Load {
+        slot: usize,
+        generation: u64, // Passed back, so stale results can be told apart
+        source: Arc<SlotSource>,
+    },
+    /// Audio the engine is finished with, so it can be freed elsewhere
+    Free(LoadedSample),
+    /// Settings the engine has replaced, which own Vecs of their own
+    Params(Box<GranularParams>),
+    /// A source the engine has forgotten, which may hold the last reference
+    Source(Arc<SlotSource>),
+}
+
+/// The outcome of a load, with errors already formatted so the audio thread needn't allocate
+pub type LoadResult = Result<LoadedSample, String>;
+
+/// A finished load, tagged with the request it answers
+#[derive(Debug)]
+pub struct Loaded {
+    pub slot: usize,
+    pub generation: u64,
+    pub result: LoadResult,
+}
+
+/// Starts a worker thread which decodes files and generates audio away from the audio thread.
+/// Loaded samples, or why they failed, come back through the receiver with their slot.
+pub fn spawn_loader() -> (Sender<LoaderMessage>, Receiver<Loaded>) {
+    let (msg_sender, msg_rcvr) = channel();
+    let (sample_sender, sample_rcvr) = channel();
+
+    std::thread::spawn(move || {
+        for msg in msg_rcvr {
+            match msg {
+                LoaderMessage::Load {
+                    slot,
+                    generation,
+                    source,
+                } => {
+                    let result = load_source(&source).map_err(|e| e.to_string());
+                    let loaded = Loaded {
+                        slot,
+                        generation,
+                        result,
+                    };
+                    if sample_sender.send(loaded).is_err() {
+                        break;
+                    }
+                }
+                // Dropped here instead of on the audio thread
+                LoaderMessage::Free(sample) => drop(sample),
+                LoaderMessage::Params(params) => drop(params),
+                LoaderMessage::Source(source) => drop(source),
+            }
+        }
+    });
+
+    (msg_sender, sample_rcvr)
+}
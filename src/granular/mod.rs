@@ -1,28 +1,61 @@
 pub mod grain;
+pub mod loader;
+pub mod onset;
+pub mod record;
+pub mod scale;
+pub mod scan;
 pub mod sequencer;
+pub mod slot;
+pub mod synth;
+pub mod tuning;
+pub mod voices;
 
-use crate::dsp::StereoFrame;
-use crate::granular::grain::EnvelopeMode;
-use eframe::emath::Pos2;
+use crate::dsp::{Interpolation, PanLaw, StereoFrame, SAMPLE_RATE};
+use crate::filters::{Filter, FilterMode};
+use crate::granular::grain::{
+    semitones_to_ratio, Breakpoint, Direction, EnvelopeMode, EnvelopeTable, GrainSettings,
+};
 use grain::Grain;
-use rodio::{Decoder, Source};
-use sequencer::Sequencer;
-use std::fs::File;
-use std::io::BufReader;
+use loader::{Loaded, LoadedSample, LoaderMessage, SlotSource};
+use onset::OnsetMode;
+use rand::Rng;
+use rand_core::SeedableRng;
+use rand_pcg::Pcg64Mcg;
+use record::{RecordMessage, Recording};
+use scale::{Chord, Scale};
+use scan::{LoopMode, Scanner, Stretch};
+use sequencer::{GrainMessage, Leaf, LeafMapping, Scheduling, Sequencer, SlotMapping};
+use slot::SampleSlot;
 use std::path::PathBuf;
-use std::sync::mpsc::Receiver;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use tuning::TuningTable;
+use voices::{StealMode, VoicePool};
 
 #[derive(Debug)]
 pub struct GranularEngine {
     path: PathBuf,
-    samples: Vec<StereoFrame>,
-    grains: Vec<Grain>,
-    params: GranularParams,
-    param_rcvr: Receiver<GranularParams>,
+    slots: Vec<SampleSlot>, // All allocated up front, unused slots are left empty
+    loader: Sender<LoaderMessage>,
+    loaded_rcvr: Receiver<Loaded>,
+    generations: [u64; Self::MAX_SLOTS], // Bumped whenever a slot's contents are replaced
+    status_sender: Sender<GranularStatus>,
+    grains: VoicePool,
+    envelope: EnvelopeTable,
+    compensation: f32, // Smoothed gain applied to the sum of overlapping grains
+    rng: Pcg64Mcg,     // Seeded, so a seed always gives the same texture
+    events: Vec<GrainMessage>, // Reused each sample to collect sequencer events
+    params: Box<GranularParams>, // Boxed by the Ui, so replacing them doesn't allocate here
+    param_rcvr: Receiver<Box<GranularParams>>,
+    no_source: Arc<SlotSource>, // Swapped in for recorded slots without allocating
     gate: bool,
     gate_rcvr: Receiver<bool>,
-    sr: u32,
     scan: bool,
+    scanner: Scanner,
+    scan_position: Arc<AtomicUsize>, // Shared with the Ui, so it can follow the scan
+    recording: Option<Recording>,
+    record_rcvr: Receiver<RecordMessage>,
     seq: Sequencer,
 }
 
@@ -33,140 +66,641 @@ pub struct GranularParams {
     pub gain: f32,
     pub start: usize,
     pub scan: Option<bool>,
-    pub file: PathBuf,
+    /// Samples moved per sample while scanning, so 1 plays at the original speed,
+    /// 0.5 stretches by 2x and negative values scan backwards
+    pub scan_rate: f32,
+    pub loop_start: f32, // Loop points as fractions of the first slot's buffer
+    pub loop_end: f32,
+    pub loop_mode: LoopMode,
+    /// Time-stretch mode, which scans and sets grain length, spread and density
+    /// from the stretch factor, leaving pitch as the only other control
+    pub stretch: bool,
+    pub stretch_factor: f32,
+    /// A file or generator for each sample slot, the first is the one scanned and shown
+    /// in the Ui. Empty paths leave their slot silent. Shared, so the engine can pass them
+    /// to the loader without copying.
+    pub sources: Vec<Arc<SlotSource>>,
+    pub slot_mapping: SlotMapping,
+    pub onset_mode: OnsetMode,
     pub density: f32, // How often grains will be spawned, in hz
+    pub scheduling: Scheduling,
+    pub regularity: f32, // 1 is synchronous, 0 fully random
+    /// Pulsar scheduling replaces the density with a fundamental, and the
+    /// grain length with a duty cycle, the fraction of each period a grain lasts
+    pub fundamental: f32,
+    pub duty: f32,
+    pub polyphony: usize, // Maximum grains playing at once
+    pub steal_mode: StealMode,
+    pub gain_compensation: GainCompensation,
+    pub pitch: f32, // Transposition in semitones
+    pub rate: f32,  // Playback speed multiplier, applied on top of pitch
+    // Pitches picked by leaves, quantised to a key
+    pub pitch_mapping: LeafMapping,
+    pub pitch_range: f32, // In semitones
+    pub root: usize,      // Index into `scale::NOTE_NAMES`
+    pub scale: Scale,
+    pub custom_scale: [bool; 12],
+    pub tuning: Option<TuningTable>, // From Scala files, 12-TET if not set
+    // Several grains spawned by each sequencer event
+    pub cloud_size: usize,
+    pub chord: Chord,
+    pub chord_interval: f32, // In semitones, for stacked chords
+    pub cloud_offset: usize, // Start offset between each grain, in samples
+    pub interpolation: Interpolation,
+    pub direction: Direction,
+    pub pan_law: PanLaw,
     pub envelope_mode: EnvelopeMode,
     pub envelope_sharpness: f32,
     pub envelope_shape: f32,
+    pub envelope_points: Vec<Breakpoint>, // Used by the custom envelope
+    // Random variation applied to each grain as it spawns
+    pub start_jitter: usize, // Maximum start offset, in samples
+    pub length_jitter: f32,  // As a fraction of the grain length
+    pub pitch_jitter: f32,   // In semitones
+    pub pan_jitter: f32,
+    pub gain_jitter: f32, // How far grains can be turned down, from 0 to 1
+    // A filter on each grain, set as it spawns
+    pub filter: bool,
+    pub filter_mode: FilterMode,
+    pub cutoff: f32,    // In hz
+    pub resonance: f32, // Filter Q
+    pub cutoff_mapping: LeafMapping,
+    pub cutoff_range: f32,     // Octaves swept by the mapped leaf property
+    pub cutoff_jitter: f32,    // In octaves
+    pub resonance_jitter: f32, // As a fraction of the resonance
+    pub seed: u64,
+}
+
+/// How the output is scaled as grains overlap
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GainCompensation {
+    Off,
+    Linear,     // 1 / N, keeps correlated grains from clipping
+    EqualPower, // 1 / sqrt(N), keeps the loudness of uncorrelated grains steady
+}
+
+impl GainCompensation {
+    /// Gain for a given amount of overlap, measured as the sum of grain envelopes
+    pub fn gain(&self, overlap: f32) -> f32 {
+        let overlap = overlap.max(1.0);
+        match self {
+            GainCompensation::Off => 1.0,
+            GainCompensation::Linear => overlap.recip(),
+            GainCompensation::EqualPower => overlap.sqrt().recip(),
+        }
+    }
+}
+
+/// Engine state reported back to the Ui
+#[derive(Debug, Clone)]
+pub struct GranularStatus {
+    pub slot: usize,
+    pub buffer_len: usize,
+    pub onsets: usize, // Number of onsets detected in the slot
+    pub recording: bool,
+    pub error: Option<String>, // Why the slot's last load failed
 }
 
 impl Default for GranularParams {
     fn default() -> Self {
         Self {
-            grain_length: 44000,
-            grain_spread: 88000,
+            grain_length: SAMPLE_RATE as usize,
+            grain_spread: 2 * SAMPLE_RATE as usize,
             gain: 0.7,
             start: 0,
             scan: None,
-            file: PathBuf::from("assets/audio/handpan_trimmed.wav"),
+            scan_rate: 1.0,
+            loop_start: 0.0,
+            loop_end: 1.0,
+            loop_mode: LoopMode::Loop,
+            stretch: false,
+            stretch_factor: 8.0,
+            sources: vec![Arc::new(SlotSource::File(PathBuf::from(
+                "assets/audio/handpan_trimmed.wav",
+            )))],
+            slot_mapping: SlotMapping::Single,
+            onset_mode: OnsetMode::Off,
             density: 1.0,
+            scheduling: Scheduling::Synchronous,
+            regularity: 0.5,
+            fundamental: 110.0,
+            duty: 0.5,
+            polyphony: 64,
+            steal_mode: StealMode::Oldest,
+            gain_compensation: GainCompensation::EqualPower,
+            pitch: 0.0,
+            rate: 1.0,
+            pitch_mapping: LeafMapping::Off,
+            pitch_range: 12.0,
+            root: 0,
+            scale: Scale::Major,
+            custom_scale: Scale::Major.steps([false; 12]),
+            tuning: None,
+            cloud_size: 1,
+            chord: Chord::Unison,
+            chord_interval: 5.0,
+            cloud_offset: 0,
+            interpolation: Interpolation::Cubic,
+            direction: Direction::Forward,
+            pan_law: PanLaw::ConstantPower,
             envelope_mode: EnvelopeMode::Smooth,
             envelope_sharpness: 0.0,
             envelope_shape: 0.5,
+            envelope_points: vec![
+                Breakpoint::new(0.0, 0.0, 0.0),
+                Breakpoint::new(0.2, 1.0, -3.0),
+                Breakpoint::new(1.0, 0.0, 3.0),
+            ],
+            start_jitter: 0,
+            length_jitter: 0.0,
+            pitch_jitter: 0.0,
+            pan_jitter: 0.0,
+            gain_jitter: 0.0,
+            filter: false,
+            filter_mode: FilterMode::Lowpass,
+            cutoff: 1000.0,
+            resonance: 1.0,
+            cutoff_mapping: LeafMapping::Off,
+            cutoff_range: 3.0,
+            cutoff_jitter: 0.0,
+            resonance_jitter: 0.0,
+            seed: 123123123,
         }
     }
 }
 
 impl GranularEngine {
+    /// Most sample slots that can be loaded at once
+    pub const MAX_SLOTS: usize = 8;
+    /// One pole coefficient for gain compensation, a time constant of around 50ms
+    const COMPENSATION_SMOOTHING: f32 = 1.0 / (0.05 * SAMPLE_RATE as f32);
+
     pub fn new(
         path: PathBuf,
-        param_rcvr: Receiver<GranularParams>,
+        param_rcvr: Receiver<Box<GranularParams>>,
         gate_rcvr: Receiver<bool>,
-        seq_rcvr: Receiver<Vec<Pos2>>,
+        seq_rcvr: Receiver<Vec<Leaf>>,
+        record_rcvr: Receiver<RecordMessage>,
+        status_sender: Sender<GranularStatus>,
     ) -> Self {
+        let (loader, loaded_rcvr) = loader::spawn_loader();
+        let defaults = GranularParams::default();
         Self {
             path,
-            samples: vec![],
-            grains: (0..64).map(|_| Grain::finished()).collect(), // Init with 64 grains
-            params: Default::default(),
+            slots: (0..Self::MAX_SLOTS).map(|_| SampleSlot::new()).collect(),
+            loader,
+            loaded_rcvr,
+            generations: [0; Self::MAX_SLOTS],
+            status_sender,
+            grains: VoicePool::new(defaults.polyphony),
+            envelope: EnvelopeTable::new(
+                defaults.envelope_mode,
+                defaults.envelope_sharpness,
+                defaults.envelope_shape,
+                &defaults.envelope_points,
+            ),
+            compensation: 1.0,
+            rng: Pcg64Mcg::seed_from_u64(defaults.seed),
+            events: Vec::with_capacity(Sequencer::EVENT_CAPACITY),
             param_rcvr,
             gate: true,
             gate_rcvr,
-            sr: 0,
             scan: false,
-            seq: Sequencer::new(vec![], 1.0, seq_rcvr),
+            scanner: Scanner::new(defaults.start),
+            scan_position: Arc::new(AtomicUsize::new(defaults.start)),
+            recording: None,
+            record_rcvr,
+            seq: Sequencer::new(vec![], 1.0, defaults.seed, seq_rcvr),
+            no_source: Arc::new(SlotSource::File(PathBuf::new())),
+            params: Box::new(defaults),
         }
     }
 
-    /// Initializes samples from path
+    /// Initializes the first slot from path, leaving it empty and reporting why on failure.
+    /// This blocks, so it should only be used before audio starts.
     pub fn init(&mut self) {
-        let reader = BufReader::new(File::open(&self.path).expect("Unknown file"));
-        let decoder = Decoder::new_wav(reader).expect("Couldn't create decoder for file");
-
-        self.sr = decoder.sample_rate();
-        self.samples = decoder.convert_samples().map(StereoFrame::new).collect();
+        match loader::load(&self.path) {
+            Ok(sample) => {
+                println!(
+                    "Loaded {} with {} channels at {} Hz",
+                    self.path.display(),
+                    sample.channels,
+                    sample.sample_rate
+                );
+                self.slots[0].set(sample);
+                self.send_status(0, None);
+            }
+            Err(e) => self.send_status(0, Some(e.to_string())),
+        }
     }
 
     pub fn update_params(&mut self) {
-        if let Ok(params) = self.param_rcvr.try_recv() {
-            if params.file != self.params.file {
-                self.path = params.file.clone();
-                self.init();
-                println!("Initialised with new file");
+        if let Ok(mut params) = self.param_rcvr.try_recv() {
+            params.sources.truncate(Self::MAX_SLOTS);
+            for (slot, source) in params.sources.iter().enumerate() {
+                if self.params.sources.get(slot) == Some(source) {
+                    continue;
+                }
+                self.generations[slot] += 1;
+                if source.is_empty() {
+                    // A new slot with nothing loaded yet, or one cleared by an empty path
+                    let retired = self.slots[slot].receive(LoadedSample::empty());
+                    self.retire(retired);
+                    self.send_status(slot, None);
+                } else {
+                    // Decoding and generating happen on the loader thread,
+                    // the result is picked up in `receive_samples`
+                    let _ = self.loader.send(LoaderMessage::Load {
+                        slot,
+                        generation: self.generations[slot],
+                        source: Arc::clone(source),
+                    });
+                }
+            }
+            // Removed slots fade out to silence
+            for slot in params.sources.len()..self.params.sources.len() {
+                self.generations[slot] += 1;
+                let retired = self.slots[slot].receive(LoadedSample::empty());
+                self.retire(retired);
+            }
+            let removed = |recording: &mut Recording| recording.slot >= params.sources.len();
+            if let Some(mut recording) = self.recording.take_if(removed) {
+                self.free(recording.take());
             }
+            self.seq.slot_count = params.sources.len();
+            self.seq.slot_mapping = params.slot_mapping;
+            self.seq.pitch_mapping = params.pitch_mapping;
+            self.seq.cutoff_mapping = params.cutoff_mapping;
+            self.seq.pitch_range = params.pitch_range;
+            self.seq.root = params.root;
+            self.seq.scale_steps = params.scale.steps(params.custom_scale);
+            self.seq.tuning = params.tuning;
 
-            // Enable or disable scanning
+            // Enable or disable scanning, starting from the start position
+            let was_scanning = self.scanning();
             if let Some(scan) = params.scan {
                 self.scan = scan;
             }
+            if params.stretch {
+                let stretch = Stretch::new(params.stretch_factor);
+                params.grain_length = stretch.grain_length;
+                params.grain_spread = stretch.grain_spread;
+                params.density = stretch.density;
+                params.scan_rate = stretch.scan_rate;
+                params.rate = 1.0;
+            }
+            if params.scheduling == Scheduling::Pulsar {
+                params.density = params.fundamental;
+                params.grain_length =
+                    ((params.duty * SAMPLE_RATE as f32 / params.fundamental) as usize).max(1);
+            }
+            if !was_scanning && (self.scan || params.stretch) {
+                self.scanner.reset(params.start);
+            }
+            // Moving the start restarts the scan from there
+            if params.start != self.params.start {
+                self.scanner.reset(params.start);
+            }
             if params.density != self.seq.rate {
                 self.seq.rate = params.density;
             }
-            // This prevents the scan restarting every time a parameter changes
-            let start = if let Some(true) = params.scan {
-                self.params.start
-            } else {
-                params.start
-            };
-
-            self.params = GranularParams { start, ..params };
-            println!("Granny received her params: \n{:?}", self.params);
+            self.grains.set_polyphony(params.polyphony);
+            if params.seed != self.params.seed {
+                self.rng = Pcg64Mcg::seed_from_u64(params.seed);
+                self.seq.reseed(params.seed);
+            }
+            self.seq.scheduling = params.scheduling;
+            self.seq.regularity = params.regularity;
+            self.envelope.update(
+                params.envelope_mode,
+                params.envelope_sharpness,
+                params.envelope_shape,
+                &params.envelope_points,
+            );
+            // The old settings own Vecs, so they're freed on the loader thread too
+            let old = std::mem::replace(&mut self.params, params);
+            let _ = self.loader.send(LoaderMessage::Params(old));
         }
         if let Ok(gate) = self.gate_rcvr.try_recv() {
             self.gate = gate;
         }
     }
 
+    /// Swaps in a newly loaded buffer, keeping the old one to fade out from
+    fn receive_samples(&mut self) {
+        if let Ok(loaded) = self.loaded_rcvr.try_recv() {
+            let slot = loaded.slot;
+            // The slot may have been changed, removed or recorded into while this was loading
+            let recording = self.recording.as_ref().is_some_and(|rec| rec.slot == slot);
+            if loaded.generation != self.generations[slot] || recording {
+                if let Ok(sample) = loaded.result {
+                    self.retire(sample);
+                }
+                return;
+            }
+            let sample = match loaded.result {
+                Ok(sample) => sample,
+                Err(e) => {
+                    // The slot keeps whatever it was playing
+                    self.send_status(slot, Some(e));
+                    return;
+                }
+            };
+            let retired = self.slots[slot].receive(sample);
+            self.retire(retired);
+            self.send_status(slot, None);
+        }
+    }
+
+    fn send_status(&self, slot: usize, error: Option<String>) {
+        let _ = self.status_sender.send(GranularStatus {
+            slot,
+            buffer_len: self.slots[slot].len(),
+            onsets: self.slots[slot].onsets().len(),
+            recording: self.recording.is_some(),
+            error,
+        });
+    }
+
+    /// Records the master output into a slot, called with each block once effects are applied
+    pub fn record(&mut self, output: &[StereoFrame]) {
+        if let Ok(msg) = self.record_rcvr.try_recv() {
+            match msg {
+                RecordMessage::Start {
+                    slot,
+                    buffer,
+                    rolling,
+                } => {
+                    self.stop_recording();
+                    if slot < self.params.sources.len() {
+                        // The slot no longer holds its source, so loading it again is a change
+                        let source = std::mem::replace(
+                            &mut self.params.sources[slot],
+                            Arc::clone(&self.no_source),
+                        );
+                        let _ = self.loader.send(LoaderMessage::Source(source));
+                        self.generations[slot] += 1;
+                        self.recording = Some(Recording::new(slot, buffer, rolling));
+                    } else {
+                        self.free(buffer);
+                    }
+                }
+                RecordMessage::Stop => self.stop_recording(),
+            }
+        }
+
+        // Taken out of self while writing, so the slot can be borrowed
+        let Some(mut recording) = self.recording.take() else {
+            return;
+        };
+        let slot = recording.slot;
+        if recording.live {
+            recording.overdub(self.slots[slot].samples_mut(), output);
+            self.recording = Some(recording);
+            return;
+        }
+
+        let written = recording.write(output);
+        if recording.full() {
+            let frames = recording.take();
+            self.bounce(slot, frames);
+            if recording.rolling {
+                // Carry on over the top of what was just recorded
+                recording.live = true;
+                recording.overdub(self.slots[slot].samples_mut(), &output[written..]);
+                self.recording = Some(recording);
+            }
+            self.send_status(slot, None);
+        } else {
+            self.recording = Some(recording);
+        }
+    }
+
+    /// Ends any recording, swapping in what has been recorded so far
+    fn stop_recording(&mut self) {
+        if let Some(mut recording) = self.recording.take() {
+            let frames = recording.take();
+            if recording.live || frames.is_empty() {
+                self.free(frames);
+            } else {
+                self.bounce(recording.slot, frames);
+                self.send_status(recording.slot, None);
+            }
+        }
+    }
+
+    /// Swaps a recording in as a slot's source
+    fn bounce(&mut self, slot: usize, frames: Vec<StereoFrame>) {
+        let retired = self.slots[slot].receive(LoadedSample {
+            frames,
+            sample_rate: SAMPLE_RATE,
+            channels: 2,
+            onsets: vec![], // Not analysed, so onset modes leave recordings unsnapped
+        });
+        self.retire(retired);
+    }
+
+    /// Hands a buffer to the loader thread so the deallocation happens there
+    fn free(&self, buffer: Vec<StereoFrame>) {
+        self.retire(LoadedSample {
+            frames: buffer,
+            ..LoadedSample::empty()
+        });
+    }
+
+    /// Hands old audio and its analysis to the loader thread to be freed
+    fn retire(&self, sample: LoadedSample) {
+        if sample.frames.capacity() > 0 || sample.onsets.capacity() > 0 {
+            let _ = self.loader.send(LoaderMessage::Free(sample));
+        }
+    }
+
+    /// The position grains currently spawn from, for the Ui to display
+    pub fn scan_position(&self) -> Arc<AtomicUsize> {
+        self.scan_position.clone()
+    }
+
+    fn scanning(&self) -> bool {
+        self.scan || self.params.stretch
+    }
+
+    fn start_position(&self) -> usize {
+        if self.scanning() {
+            self.scanner.position()
+        } else {
+            self.params.start
+        }
+    }
+
     pub fn buffer_size(&self) -> usize {
-        self.samples.len()
+        self.slots[0].len()
     }
 
-    pub fn spawn_grain_at(&mut self, start: usize, pan: f32) {
-        self.grains.push(Grain::new(
-            self.params.grain_length,
+    /// Where a grain from the sequencer starts, snapped to or picked from onsets
+    fn grain_start(&self, msg: &GrainMessage) -> usize {
+        let start = self.start_position() + (msg.start * self.params.grain_spread as f32) as usize;
+        let Some(slot) = self.slots.get(msg.slot) else {
+            return start;
+        };
+        let onsets = slot.onsets();
+        if onsets.is_empty() {
+            return start;
+        }
+        // Wrapped into the grain's own slot as reading would, so late starts
+        // don't all pile onto the last onset
+        let start = start % slot.len().max(1);
+        match self.params.onset_mode {
+            OnsetMode::Off => start,
+            OnsetMode::Snap => onset::nearest(onsets, start),
+            OnsetMode::Slices => {
+                let slice = (msg.start * onsets.len() as f32) as usize;
+                onsets[slice.min(onsets.len() - 1)]
+            }
+        }
+    }
+
+    /// Spawns a grain, with `pitch` in semitones on top of the pitch param.
+    /// `cutoff` is a leaf position from 0 to 1, sweeping the filter over its range.
+    pub fn spawn_grain_at(
+        &mut self,
+        start: usize,
+        pan: f32,
+        slot: usize,
+        pitch: f32,
+        cutoff: Option<f32>,
+        delay: f32,
+    ) {
+        // Leaves mapped to an empty slot stay silent
+        if self.slots.get(slot).is_none_or(SampleSlot::is_empty) {
+            return;
+        }
+        let rng = &mut self.rng;
+        let start_offset = jitter(rng, self.params.start_jitter as f32) as i64;
+        let start = (start as i64 + start_offset).max(0) as usize;
+        let length_scale = 1.0 + jitter(rng, self.params.length_jitter);
+        let length = ((self.params.grain_length as f32 * length_scale) as usize).max(1);
+        let pitch = self.params.pitch + pitch + jitter(rng, self.params.pitch_jitter);
+        let pan = (pan + jitter(rng, self.params.pan_jitter)).clamp(-1.0, 1.0);
+        let gain = 1.0 - self.params.gain_jitter * rng.random::<f32>();
+        let filter = self.params.filter.then(|| {
+            let octaves = cutoff.unwrap_or(0.0) * self.params.cutoff_range
+                + jitter(rng, self.params.cutoff_jitter);
+            let resonance =
+                self.params.resonance * (1.0 + jitter(rng, self.params.resonance_jitter));
+            Filter::new(
+                SAMPLE_RATE as usize,
+                self.params.filter_mode,
+                self.params.cutoff * octaves.exp2(),
+                resonance,
+            )
+        });
+
+        let pan_gains = if self.slots[slot].stereo {
+            self.params.pan_law.balance(pan)
+        } else {
+            self.params.pan_law.gains(pan)
+        };
+        let speed = self.params.rate * semitones_to_ratio(pitch);
+        let grain = Grain::new(GrainSettings {
+            length,
             start,
-            pan,
-            (self.grains.len() as u16).max(1),
-            self.params.envelope_mode,
-            self.params.envelope_sharpness,
-            self.params.envelope_shape,
-        ));
+            slot,
+            phase: (delay * speed) as f64,
+            pan_gains,
+            gain,
+            speed,
+            direction: self.params.direction.resolve(rng),
+            interpolation: self.params.interpolation,
+            filter,
+        });
+        self.grains
+            .spawn(grain, self.params.steal_mode, &self.envelope);
     }
 
     // Return one frame of granular audio
     pub fn process(&mut self) -> StereoFrame {
         self.update_params();
+        self.receive_samples();
         self.seq.update();
 
         // Keep delay processing even when gate is not pressed
         let mut dry = StereoFrame(0.0, 0.0);
 
-        // Remove finished grains
-        self.grains.retain(|grain| !grain.finished);
-
         // Spawn new grains if Gate is pressed
         if self.gate {
-            for msg in self.seq.get_events() {
-                let start =
-                    self.params.start + (msg.start * self.params.grain_spread as f32) as usize;
-                self.spawn_grain_at(start, msg.pan);
+            // Taken out of self while spawning, an empty Vec doesn't allocate
+            let mut events = std::mem::take(&mut self.events);
+            self.seq.take_events(&mut events);
+            for msg in &events {
+                let start = self.grain_start(msg);
+                let pitch = msg.pitch.unwrap_or(0.0);
+                for voice in 0..self.params.cloud_size {
+                    let interval = self
+                        .params
+                        .chord
+                        .interval(voice, self.params.chord_interval);
+                    let offset = voice * self.params.cloud_offset;
+                    self.spawn_grain_at(
+                        start + offset,
+                        msg.pan,
+                        msg.slot,
+                        pitch + interval,
+                        msg.cutoff,
+                        msg.delay,
+                    );
+                }
             }
+            self.events = events;
 
-            if self.scan {
-                self.params.start += 1;
+            // The scan follows the first slot, so there's nothing to scan without it
+            if self.scanning() && !self.slots[0].is_empty() {
+                let len = self.slots[0].len() as f32;
+                self.scanner.advance(
+                    self.params.scan_rate,
+                    (self.params.loop_start * len) as usize,
+                    (self.params.loop_end * len) as usize,
+                    self.params.loop_mode,
+                );
             }
         }
+        self.scan_position
+            .store(self.start_position(), Ordering::Relaxed);
 
         // Read grains even if gate is not pressed, for smooth decay
-        for grain in &mut self.grains {
-            dry += grain.read(&self.samples).scale(self.params.gain * 1.2);
+        let mut overlap = 0.0;
+        for grain in self.grains.iter_mut() {
+            let frame = self.slots[grain.slot()].frame(grain, &self.envelope);
+            let frame = grain.filter(frame);
+            overlap += grain.env(&self.envelope);
+            grain.advance();
+            dry += frame;
+        }
+
+        // Smoothed so grains starting and ending don't cause the level to pump
+        let target = self.params.gain_compensation.gain(overlap);
+        self.compensation += (target - self.compensation) * Self::COMPENSATION_SMOOTHING;
+        dry = dry.scale(self.compensation * self.params.gain * 1.2);
+
+        for slot in 0..self.slots.len() {
+            if let Some(retired) = self.slots[slot].advance() {
+                self.free(retired);
+            }
         }
         dry
     }
 
     pub fn process_block(&mut self, buf: &mut [StereoFrame]) {
-        for i in 0..buf.len() {
-            buf[i] = self.process();
+        for frame in buf.iter_mut() {
+            *frame = self.process();
         }
     }
 }
+
+/// A random offset of up to `amount` either way
+fn jitter(rng: &mut impl Rng, amount: f32) -> f32 {
+    if amount == 0.0 {
+        0.0
+    } else {
+        amount * rng.random_range(-1.0..=1.0)
+    }
+}
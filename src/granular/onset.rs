@@ -0,0 +1,143 @@
+use crate::dsp::{StereoFrame, SAMPLE_RATE};
+
+/// How grain starts use the onsets detected in their slot
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnsetMode {
+    Off,
+    Snap,   // Move each start to the nearest onset
+    Slices, // Leaf height picks a slice directly, ignoring start and spread
+}
+
+impl OnsetMode {
+    pub const ALL: [OnsetMode; 3] = [OnsetMode::Off, OnsetMode::Snap, OnsetMode::Slices];
+}
+
+/// Samples between energy measurements
+const HOP: usize = 512;
+/// Samples each energy measurement covers
+const WINDOW: usize = 2 * HOP;
+/// Scales energy before log compression, so quiet noise doesn't read as a rise
+const COMPRESSION: f32 = 1000.0;
+/// Hops either side averaged for the adaptive threshold
+const MEAN_HOPS: usize = 8;
+/// Hops either side an onset has to be the largest rise within
+const PEAK_HOPS: usize = 2;
+/// How far a rise has to clear the local average to count
+const DELTA: f32 = 0.2;
+/// Shortest gap between onsets, 50ms
+const MIN_GAP: usize = SAMPLE_RATE as usize / 20;
+
+/// Finds transients with an energy novelty curve, the rise in log energy between hops.
+/// Peaks of the curve above a local average are taken as onsets, returned in samples.
+pub fn detect(frames: &[StereoFrame]) -> Vec<usize> {
+    let energies: Vec<f32> = (0..frames.len())
+        .step_by(HOP)
+        .map(|start| {
+            let window = &frames[start..(start + WINDOW).min(frames.len())];
+            let energy = window
+                .iter()
+                .map(|frame| (frame.0 * frame.0 + frame.1 * frame.1) * 0.5)
+                .sum::<f32>()
+                / window.len() as f32;
+            (1.0 + COMPRESSION * energy).ln()
+        })
+        .collect();
+
+    // Only rises in energy, the first hop rises from silence
+    let novelty: Vec<f32> = energies
+        .iter()
+        .enumerate()
+        .map(|(i, &energy)| {
+            let previous = if i == 0 { 0.0 } else { energies[i - 1] };
+            (energy - previous).max(0.0)
+        })
+        .collect();
+
+    let mut onsets = vec![];
+    for (i, &value) in novelty.iter().enumerate() {
+        let local = around(&novelty, i, MEAN_HOPS);
+        let mean = local.iter().sum::<f32>() / local.len() as f32;
+        let peak = around(&novelty, i, PEAK_HOPS)
+            .iter()
+            .all(|&other| other <= value);
+        if !peak || value < mean + DELTA {
+            continue;
+        }
+
+        // The rise comes from the newest hop in the window
+        let position = if i == 0 { 0 } else { i * HOP + WINDOW - HOP };
+        if onsets.last().is_none_or(|&last| position >= last + MIN_GAP) {
+            onsets.push(position.min(frames.len() - 1));
+        }
+    }
+    onsets
+}
+
+/// Values up to `hops` either side of `i`
+fn around(values: &[f32], i: usize, hops: usize) -> &[f32] {
+    &values[i.saturating_sub(hops)..(i + hops + 1).min(values.len())]
+}
+
+/// The onset closest to `position`, or `position` itself if there are none
+pub fn nearest(onsets: &[usize], position: usize) -> usize {
+    let after = onsets.partition_point(|&onset| onset < position);
+    let before = after.checked_sub(1).map(|i| onsets[i]);
+    match (before, onsets.get(after)) {
+        (Some(before), Some(&after)) => {
+            if position - before <= after - position {
+                before
+            } else {
+                after
+            }
+        }
+        (Some(onset), None) | (None, Some(&onset)) => onset,
+        (None, None) => position,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_finds_impulses() {
+        let impulses = [10_000, 32_000, 54_000];
+        let mut frames = vec![StereoFrame(0.0, 0.0); 2 * SAMPLE_RATE as usize];
+        for &impulse in &impulses {
+            frames[impulse] = StereoFrame(1.0, 1.0);
+        }
+        let onsets = detect(&frames);
+        assert_eq!(onsets.len(), impulses.len(), "{onsets:?}");
+        for (onset, impulse) in onsets.iter().zip(impulses) {
+            assert!(
+                onset.abs_diff(impulse) <= HOP,
+                "{onset} is far from {impulse}"
+            );
+        }
+    }
+
+    #[test]
+    fn detect_ignores_silence() {
+        assert!(detect(&vec![StereoFrame(0.0, 0.0); SAMPLE_RATE as usize]).is_empty());
+    }
+
+    #[test]
+    fn nearest_picks_the_closest_onset() {
+        let onsets = [100, 200, 400];
+        assert_eq!(nearest(&onsets, 0), 100);
+        assert_eq!(nearest(&onsets, 260), 200);
+        assert_eq!(nearest(&onsets, 310), 400);
+        assert_eq!(nearest(&onsets, 1000), 400);
+        assert_eq!(nearest(&onsets, 200), 200);
+    }
+
+    #[test]
+    fn nearest_prefers_the_earlier_onset_on_ties() {
+        assert_eq!(nearest(&[100, 200], 150), 100);
+    }
+
+    #[test]
+    fn nearest_without_onsets_keeps_the_position() {
+        assert_eq!(nearest(&[], 123), 123);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::dsp::StereoFrame;
+
+/// Messages from the Ui controlling the resampler
+#[derive(Debug)]
+pub enum RecordMessage {
+    /// Starts recording the master output into `slot`. The buffer is allocated by the Ui,
+    /// and its length sets how long the recording is.
+    Start {
+        slot: usize,
+        buffer: Vec<StereoFrame>,
+        rolling: bool,
+    },
+    /// Stops recording, swapping in whatever has been recorded so far
+    Stop,
+}
+
+/// A recording of the engine output, which becomes a slot's source once it's full
+#[derive(Debug)]
+pub struct Recording {
+    pub slot: usize,
+    buffer: Vec<StereoFrame>, // Filled before being swapped into the slot
+    position: usize,
+    /// Keeps writing into the slot in a loop after the first pass, so grains
+    /// play back their own output
+    pub rolling: bool,
+    pub live: bool, // Writing straight into the slot's buffer
+}
+
+impl Recording {
+    pub fn new(slot: usize, buffer: Vec<StereoFrame>, rolling: bool) -> Self {
+        Self {
+            slot,
+            buffer,
+            position: 0,
+            rolling,
+            live: false,
+        }
+    }
+
+    /// Copies output into the recording, returning the number of frames used.
+    /// Fewer than `output.len()` means the buffer is full.
+    pub fn write(&mut self, output: &[StereoFrame]) -> usize {
+        let space = self.buffer.len() - self.position;
+        let count = space.min(output.len());
+        self.buffer[self.position..self.position + count].copy_from_slice(&output[..count]);
+        self.position += count;
+        count
+    }
+
+    pub fn full(&self) -> bool {
+        self.position >= self.buffer.len()
+    }
+
+    /// Takes the recorded audio, dropping anything past the write position.
+    /// The write position restarts, ready for rolling over the slot's buffer.
+    pub fn take(&mut self) -> Vec<StereoFrame> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.truncate(self.position);
+        self.position = 0;
+        buffer
+    }
+
+    /// Writes output over `target` in a loop, for feedback granulation
+    pub fn overdub(&mut self, target: &mut [StereoFrame], output: &[StereoFrame]) {
+        if target.is_empty() {
+            return;
+        }
+        for frame in output {
+            self.position %= target.len();
+            target[self.position] = *frame;
+            self.position += 1;
+        }
+    }
+}
@@ -0,0 +1,127 @@
+/// Scales that pitches from the sequencer are quantised to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scale {
+    Chromatic,
+    Major,
+    Minor,
+    Pentatonic,
+    Custom, // Notes picked in the Ui
+}
+
+/// Names of the notes in an octave, for choosing the root
+pub const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+impl Scale {
+    pub const ALL: [Scale; 5] = [
+        Scale::Chromatic,
+        Scale::Major,
+        Scale::Minor,
+        Scale::Pentatonic,
+        Scale::Custom,
+    ];
+
+    /// Which of the 12 semitones above the root are in the scale
+    pub fn steps(&self, custom: [bool; 12]) -> [bool; 12] {
+        match self {
+            Scale::Chromatic => [true; 12],
+            Scale::Major => mask(&[0, 2, 4, 5, 7, 9, 11]),
+            Scale::Minor => mask(&[0, 2, 3, 5, 7, 8, 10]),
+            Scale::Pentatonic => mask(&[0, 2, 4, 7, 9]),
+            Scale::Custom => custom,
+        }
+    }
+}
+
+/// Intervals for the grains spawned together by one sequencer event
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chord {
+    Unison, // A cluster, all at the same pitch
+    Octaves,
+    Fifths,
+    Major,
+    Minor,
+    Seventh,
+    Stack, // Repeats a chosen interval
+}
+
+impl Chord {
+    pub const ALL: [Chord; 7] = [
+        Chord::Unison,
+        Chord::Octaves,
+        Chord::Fifths,
+        Chord::Major,
+        Chord::Minor,
+        Chord::Seventh,
+        Chord::Stack,
+    ];
+
+    /// Semitones above the event's pitch for the nth grain. Triads and sevenths
+    /// carry on into the octaves above once every note has been used.
+    pub fn interval(&self, voice: usize, stack: f32) -> f32 {
+        let voicing = |notes: &[f32]| {
+            let octave = (voice / notes.len()) as f32;
+            12.0 * octave + notes[voice % notes.len()]
+        };
+        match self {
+            Chord::Unison => 0.0,
+            Chord::Octaves => 12.0 * voice as f32,
+            Chord::Fifths => 7.0 * voice as f32,
+            Chord::Major => voicing(&[0.0, 4.0, 7.0]),
+            Chord::Minor => voicing(&[0.0, 3.0, 7.0]),
+            Chord::Seventh => voicing(&[0.0, 4.0, 7.0, 10.0]),
+            Chord::Stack => stack * voice as f32,
+        }
+    }
+}
+
+fn mask(degrees: &[usize]) -> [bool; 12] {
+    let mut steps = [false; 12];
+    for &degree in degrees {
+        steps[degree] = true;
+    }
+    steps
+}
+
+/// Rounds semitones above the root to the nearest note in the scale.
+/// An empty scale rounds to the nearest semitone.
+pub fn quantise(semitones: f32, steps: [bool; 12]) -> f32 {
+    let nearest = semitones.round() as i32;
+    // A note from any scale is always within 6 semitones
+    (nearest - 6..=nearest + 6)
+        .filter(|note| steps[note.rem_euclid(12) as usize])
+        .min_by(|a, b| {
+            let distance = |note: &i32| (*note as f32 - semitones).abs();
+            distance(a).total_cmp(&distance(b))
+        })
+        .unwrap_or(nearest) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAJOR: [bool; 12] = [
+        true, false, true, false, true, true, false, true, false, true, false, true,
+    ];
+
+    #[test]
+    fn quantise_rounds_to_scale_notes() {
+        assert_eq!(quantise(1.2, MAJOR), 2.0);
+        assert_eq!(quantise(0.4, MAJOR), 0.0);
+        assert_eq!(quantise(6.3, MAJOR), 7.0);
+    }
+
+    #[test]
+    fn quantise_wraps_octaves() {
+        assert_eq!(quantise(13.2, MAJOR), 14.0);
+        // B below the root
+        assert_eq!(quantise(-1.2, MAJOR), -1.0);
+    }
+
+    #[test]
+    fn empty_scale_rounds_to_semitones() {
+        assert_eq!(quantise(3.6, [false; 12]), 4.0);
+    }
+}
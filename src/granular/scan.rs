@@ -0,0 +1,148 @@
+use crate::dsp::SAMPLE_RATE;
+
+/// What happens when the scan reaches a loop point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopMode {
+    Loop,     // Jump back to the other loop point
+    PingPong, // Reverse direction
+    OneShot,  // Stop at the loop point
+}
+
+/// Moves the grain start position through the buffer over time
+#[derive(Debug)]
+pub struct Scanner {
+    position: f64,  // In samples, fractional so slow rates can be used
+    direction: f64, // Flipped by ping-pong looping
+}
+
+impl Scanner {
+    pub fn new(position: usize) -> Self {
+        Self {
+            position: position as f64,
+            direction: 1.0,
+        }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position.max(0.0) as usize
+    }
+
+    pub fn reset(&mut self, position: usize) {
+        self.position = position as f64;
+        self.direction = 1.0;
+    }
+
+    /// Moves on by `rate` samples, keeping inside the loop points, given in samples
+    pub fn advance(&mut self, rate: f32, loop_in: usize, loop_out: usize, mode: LoopMode) {
+        let (start, end) = (loop_in.min(loop_out) as f64, loop_in.max(loop_out) as f64);
+        let length = end - start;
+        if length < 1.0 {
+            self.position = start;
+            return;
+        }
+
+        self.position += rate as f64 * self.direction;
+        if (start..end).contains(&self.position) {
+            return;
+        }
+
+        match mode {
+            LoopMode::Loop => {
+                self.position = start + (self.position - start).rem_euclid(length);
+            }
+            LoopMode::PingPong => {
+                // Reflect off whichever end was passed
+                let overshoot = if self.position >= end {
+                    self.position - end
+                } else {
+                    start - self.position
+                };
+                let overshoot = overshoot.min(length);
+                self.position = if self.position >= end {
+                    end - overshoot
+                } else {
+                    start + overshoot
+                };
+                self.direction = -self.direction;
+            }
+            LoopMode::OneShot => {
+                self.position = self.position.clamp(start, end);
+            }
+        }
+    }
+}
+
+/// Grain settings derived from a single time-stretch factor,
+/// using overlapping windows that creep through the source like PaulStretch
+#[derive(Debug, Clone, Copy)]
+pub struct Stretch {
+    pub grain_length: usize,
+    pub grain_spread: usize,
+    pub density: f32,
+    pub scan_rate: f32,
+}
+
+impl Stretch {
+    /// Grains overlapping at any time, enough to smooth over the windows
+    const OVERLAP: f32 = 4.0;
+
+    pub fn new(factor: f32) -> Self {
+        let factor = factor.max(1.0);
+        // Longer windows for larger factors, which smear transients but keep tone steady
+        let window = (0.1 * factor.sqrt()).clamp(0.05, 0.5);
+        let grain_length = (window * SAMPLE_RATE as f32) as usize;
+        Self {
+            grain_length,
+            // A little spread decorrelates overlapping grains without blurring the timeline
+            grain_spread: grain_length / 4,
+            density: Self::OVERLAP / window,
+            scan_rate: factor.recip(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loop_wraps_to_start() {
+        let mut scanner = Scanner::new(90);
+        scanner.advance(15.0, 0, 100, LoopMode::Loop);
+        assert_eq!(scanner.position(), 5);
+        // Backwards wraps to the end
+        scanner.advance(-10.0, 0, 100, LoopMode::Loop);
+        assert_eq!(scanner.position(), 95);
+    }
+
+    #[test]
+    fn ping_pong_reflects() {
+        let mut scanner = Scanner::new(90);
+        scanner.advance(15.0, 0, 100, LoopMode::PingPong);
+        assert_eq!(scanner.position(), 95);
+        // Now heading back towards the start
+        scanner.advance(15.0, 0, 100, LoopMode::PingPong);
+        assert_eq!(scanner.position(), 80);
+    }
+
+    #[test]
+    fn one_shot_stops_at_the_end() {
+        let mut scanner = Scanner::new(90);
+        scanner.advance(15.0, 0, 100, LoopMode::OneShot);
+        assert_eq!(scanner.position(), 100);
+    }
+
+    #[test]
+    fn loop_points_can_be_swapped() {
+        let mut scanner = Scanner::new(50);
+        scanner.advance(60.0, 100, 0, LoopMode::Loop);
+        assert_eq!(scanner.position(), 10);
+    }
+
+    #[test]
+    fn empty_loop_holds_the_start() {
+        let mut scanner = Scanner::new(50);
+        scanner.advance(1.0, 20, 20, LoopMode::Loop);
+        assert_eq!(scanner.position(), 20);
+    }
+}
@@ -1,64 +1,228 @@
+use crate::dsp::SAMPLE_RATE;
+use crate::granular::scale::quantise;
+use crate::granular::tuning::TuningTable;
 use eframe::epaint::Pos2;
-use rand::prelude::IndexedRandom;
-use rand::rng;
+use rand::Rng;
+use rand_core::SeedableRng;
+use rand_pcg::Pcg64Mcg;
+use std::f32::consts::TAU;
 use std::sync::mpsc::Receiver;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct GrainMessage {
     // The start as a percentage of a total length, in this version, the whole sample
     pub start: f32,
     pub pan: f32,
+    pub slot: usize, // Sample slot to play from
+    /// Transposition in semitones, added to the engine's pitch
+    pub pitch: Option<f32>,
+    /// Leaf position from 0 to 1, sweeping the grain filter's cutoff
+    pub cutoff: Option<f32>,
+    /// How long ago the grain was due, in fractions of a sample
+    pub delay: f32,
+}
+
+/// A leaf on the plant, with the properties the sequencer can map from
+#[derive(Debug, Clone, Copy)]
+pub struct Leaf {
+    pub pos: Pos2,
+    pub colour: usize, // Index into the plant's leaf colours
+    pub depth: usize,  // Number of branches between the leaf and the trunk
+}
+
+/// Which leaf property picks the sample slot a grain plays from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlotMapping {
+    Single, // Everything plays from the first slot
+    Colour, // The colour of the leaf
+    Depth,  // How many branches deep the leaf is
+    Side,   // Left or right of the trunk
+}
+
+impl SlotMapping {
+    pub const ALL: [SlotMapping; 4] = [
+        SlotMapping::Single,
+        SlotMapping::Colour,
+        SlotMapping::Depth,
+        SlotMapping::Side,
+    ];
+}
+
+/// Which leaf property sets a continuous grain parameter, such as pitch
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeafMapping {
+    Off,
+    Height, // Higher leaves give higher values
+    X,      // Left to right goes from low to high
+    Depth,  // Deeper branches give higher values
+}
+
+impl LeafMapping {
+    pub const ALL: [LeafMapping; 4] = [
+        LeafMapping::Off,
+        LeafMapping::Height,
+        LeafMapping::X,
+        LeafMapping::Depth,
+    ];
+}
+
+/// How the time between grains is chosen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scheduling {
+    Synchronous, // A fixed period from the density
+    Poisson,     // Exponentially distributed gaps, as in asynchronous granular synthesis
+    Gaussian,    // Gaps spread either side of the period
+    /// Phase locked to a fundamental at audio rate, so the rate is heard as pitch
+    Pulsar,
+}
+
+impl Scheduling {
+    pub const ALL: [Scheduling; 4] = [
+        Scheduling::Synchronous,
+        Scheduling::Poisson,
+        Scheduling::Gaussian,
+        Scheduling::Pulsar,
+    ];
+
+    /// Whether the regularity blend applies
+    pub fn random(&self) -> bool {
+        matches!(self, Scheduling::Poisson | Scheduling::Gaussian)
+    }
 }
 
 #[derive(Debug)]
 pub struct Sequencer {
-    points: Vec<Pos2>, // Untransformed points
+    points: Vec<Leaf>, // Untransformed points
     max_height: f32,
+    max_depth: usize,
     pub rate: f32,
+    pub scheduling: Scheduling,
+    /// Blends random gaps towards the fixed period, 1 is fully synchronous
+    pub regularity: f32,
+    rng: Pcg64Mcg,
+    pub slot_mapping: SlotMapping,
+    pub slot_count: usize, // Number of slots leaves are spread across
+    pub pitch_mapping: LeafMapping,
+    pub cutoff_mapping: LeafMapping,
+    pub pitch_range: f32,        // Semitones between the lowest and highest leaf
+    pub root: usize,             // Semitones above the sample's own pitch
+    pub scale_steps: [bool; 12], // Semitones above the root that pitches are quantised to
+    pub tuning: Option<TuningTable>, // Keys are mapped through this instead of 12-TET
     timer: usize,
-    points_receiver: Receiver<Vec<Pos2>>,
+    phase: f64, // Fraction of a period, for pulsar scheduling
+    points_receiver: Receiver<Vec<Leaf>>,
     grain_events: Vec<GrainMessage>,
     index: usize,
 }
 
 impl Sequencer {
-    pub fn new(points: Vec<Pos2>, rate: f32, rcvr: Receiver<Vec<Pos2>>) -> Self {
+    pub fn new(points: Vec<Leaf>, rate: f32, seed: u64, rcvr: Receiver<Vec<Leaf>>) -> Self {
         Self {
             points,
             max_height: 0.0,
+            max_depth: 0,
             rate,
+            scheduling: Scheduling::Synchronous,
+            regularity: 0.5,
+            rng: Pcg64Mcg::seed_from_u64(Self::scheduling_seed(seed)),
+            slot_mapping: SlotMapping::Single,
+            slot_count: 1,
+            pitch_mapping: LeafMapping::Off,
+            cutoff_mapping: LeafMapping::Off,
+            pitch_range: 12.0,
+            root: 0,
+            scale_steps: [true; 12],
+            tuning: None,
             timer: 0,
+            phase: 0.0,
             points_receiver: rcvr,
-            grain_events: vec![],
+            grain_events: Vec::with_capacity(Self::EVENT_CAPACITY),
             index: 0,
         }
     }
 
-    pub fn get_events(&mut self) -> Vec<GrainMessage> {
-        self.grain_events
-            .drain(0..self.grain_events.len())
-            .collect()
+    /// Events reserved up front, so triggering doesn't allocate on the audio thread
+    pub const EVENT_CAPACITY: usize = 64;
+
+    /// Moves pending events into `out`, keeping the capacity of both buffers
+    pub fn take_events(&mut self, out: &mut Vec<GrainMessage>) {
+        out.clear();
+        out.append(&mut self.grain_events);
     }
 
     pub fn update_points(&mut self) {
         if let Ok(points) = self.points_receiver.try_recv() {
             self.max_height = points
                 .iter()
-                .map(|p| p.y)
+                .map(|leaf| leaf.pos.y)
                 .max_by(|p1, p2| p1.total_cmp(p2))
                 .unwrap_or(0.0);
+            self.max_depth = points.iter().map(|leaf| leaf.depth).max().unwrap_or(0);
             self.points = points
         }
     }
 
+    /// Offset from the engine's seed, so timing isn't correlated with grain jitter
+    fn scheduling_seed(seed: u64) -> u64 {
+        seed.wrapping_add(1)
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = Pcg64Mcg::seed_from_u64(Self::scheduling_seed(seed));
+    }
+
+    /// Longest random gap, as a multiple of the period
+    const MAX_GAP: f32 = 8.0;
+    /// Standard deviation of Gaussian gaps, as a fraction of the period
+    const GAUSSIAN_DEVIATION: f32 = 0.5;
+
+    fn period(&self) -> f32 {
+        SAMPLE_RATE as f32 / self.rate
+    }
+
+    /// Samples until the next grain
+    fn next_gap(&mut self) -> usize {
+        let period = self.period();
+        let random = match self.scheduling {
+            Scheduling::Synchronous | Scheduling::Pulsar => return period as usize,
+            // Exponential gaps, which give a Poisson process at the density
+            Scheduling::Poisson => -period * (1.0 - self.rng.random::<f32>()).ln(),
+            Scheduling::Gaussian => {
+                // Box-Muller transform
+                let radius = (-2.0 * (1.0 - self.rng.random::<f32>()).ln()).sqrt();
+                let normal = radius * (TAU * self.rng.random::<f32>()).cos();
+                period * (1.0 + Self::GAUSSIAN_DEVIATION * normal)
+            }
+        };
+        let gap = period + (random - period) * (1.0 - self.regularity);
+        gap.clamp(1.0, period * Self::MAX_GAP) as usize
+    }
+
     pub fn update(&mut self) {
         self.update_points();
+        if self.scheduling == Scheduling::Pulsar {
+            // A fractional phase, so the average period is exact at audio rates
+            let increment = self.rate as f64 / SAMPLE_RATE as f64;
+            self.phase += increment;
+            if self.phase >= 1.0 {
+                self.phase = self.phase.fract();
+                // The pulse fell between samples, so the grain catches up by the overshoot
+                self.trigger((self.phase / increment) as f32);
+            }
+            return;
+        }
+
         if self.timer == 0 {
-            self.trigger();
-            self.timer = (44000.0 / self.rate) as usize
+            self.trigger(0.0);
+            self.timer = self.next_gap();
         }
         // Clamp to make sure timer resets with rate increasing
-        self.timer = self.timer.clamp(0, (44000.0 / self.rate) as usize);
+        let longest = if self.scheduling.random() {
+            self.period() * Self::MAX_GAP
+        } else {
+            self.period()
+        };
+        self.timer = self.timer.clamp(1, longest.max(1.0) as usize);
         self.timer -= 1;
     }
 
@@ -66,7 +230,7 @@ impl Sequencer {
     /// Smaller values make the pan effect more pronounced on narrower trees
     const PAN_SENSITIVITY: f32 = 180.0;
 
-    pub fn trigger(&mut self) {
+    pub fn trigger(&mut self, delay: f32) {
         // if let Some(pos) = self.points.choose(&mut rng()) {
         //     let start = pos.y / self.max_height;
         //     let pan = (pos.x - 250.0) / 250.0;
@@ -76,12 +240,56 @@ impl Sequencer {
         let points_len = self.points.len();
         if points_len > 0 {
             self.index = (self.index + 1) % (points_len - 1);
-            let pos = self.points[self.index.min(points_len - 1)];
+            let leaf = self.points[self.index.min(points_len - 1)];
+            let pos = leaf.pos;
 
             let start = pos.y / self.max_height;
             let pan = ((pos.x - Self::PAN_SENSITIVITY) / Self::PAN_SENSITIVITY).clamp(-1.0, 1.0);
-            let msg = GrainMessage { start, pan };
+            let slot = self.slot(&leaf, pan);
+            let pitch = self.pitch(&leaf, start, pan);
+            let cutoff = self.position(self.cutoff_mapping, &leaf, start, pan);
+            let msg = GrainMessage {
+                start,
+                pan,
+                slot,
+                pitch,
+                cutoff,
+                delay,
+            };
             self.grain_events.push(msg);
         }
     }
+
+    /// Where a leaf sits from 0 to 1 along the mapped property
+    fn position(&self, mapping: LeafMapping, leaf: &Leaf, height: f32, pan: f32) -> Option<f32> {
+        match mapping {
+            LeafMapping::Off => None,
+            LeafMapping::Height => Some(height),
+            LeafMapping::X => Some((pan + 1.0) / 2.0),
+            LeafMapping::Depth => Some(leaf.depth as f32 / self.max_depth.max(1) as f32),
+        }
+    }
+
+    /// Picks a pitch for a leaf, quantised to the scale and then tuned
+    fn pitch(&self, leaf: &Leaf, height: f32, pan: f32) -> Option<f32> {
+        let position = self.position(self.pitch_mapping, leaf, height, pan)?;
+        let offset = position * self.pitch_range;
+        match &self.tuning {
+            // The tuning's keys are its scale, so the 12-TET scale mask doesn't apply
+            Some(tuning) => Some(tuning.semitones(self.root as i32 + offset.round() as i32)),
+            None => Some(self.root as f32 + quantise(offset, self.scale_steps)),
+        }
+    }
+
+    /// Picks the sample slot for a leaf, wrapping colours and depths around the slot count
+    fn slot(&self, leaf: &Leaf, pan: f32) -> usize {
+        let count = self.slot_count.max(1);
+        match self.slot_mapping {
+            SlotMapping::Single => 0,
+            SlotMapping::Colour => leaf.colour % count,
+            SlotMapping::Depth => leaf.depth % count,
+            // Split the width of the tree into one band per slot, left to right
+            SlotMapping::Side => (((pan + 1.0) / 2.0 * count as f32) as usize).min(count - 1),
+        }
+    }
 }
@@ -0,0 +1,105 @@
+use crate::dsp::{StereoFrame, SAMPLE_RATE};
+use crate::granular::grain::{EnvelopeTable, Grain};
+use crate::granular::loader::LoadedSample;
+use std::f32::consts::FRAC_PI_2;
+
+/// A source buffer, which crossfades from its old audio when something new is loaded
+#[derive(Debug)]
+pub struct SampleSlot {
+    samples: Vec<StereoFrame>,
+    previous: Vec<StereoFrame>, // The old buffer, faded out after a new one arrives
+    fade: f32,                  // Crossfade progress from 0 to 1
+    onsets: Vec<usize>,         // Transients in the current buffer
+    pub stereo: bool,           // Stereo sources are balanced rather than panned
+    pub sample_rate: u32,       // Of the source file, before conversion
+}
+
+impl SampleSlot {
+    /// Time taken to crossfade between the old and new buffer when a file is loaded
+    const CROSSFADE_LENGTH: usize = SAMPLE_RATE as usize / 4;
+
+    pub fn new() -> Self {
+        Self {
+            samples: vec![],
+            previous: vec![],
+            fade: 1.0,
+            onsets: vec![],
+            stereo: false,
+            sample_rate: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// The current buffer, for recording straight into
+    pub fn samples_mut(&mut self) -> &mut [StereoFrame] {
+        &mut self.samples
+    }
+
+    pub fn onsets(&self) -> &[usize] {
+        &self.onsets
+    }
+
+    /// Swaps in new audio without fading, returning the old audio to be freed
+    pub fn set(&mut self, sample: LoadedSample) -> LoadedSample {
+        let retired = LoadedSample {
+            frames: std::mem::replace(&mut self.samples, sample.frames),
+            sample_rate: self.sample_rate,
+            channels: if self.stereo { 2 } else { 1 },
+            onsets: std::mem::replace(&mut self.onsets, sample.onsets),
+        };
+        self.stereo = sample.channels > 1;
+        self.sample_rate = sample.sample_rate;
+        retired
+    }
+
+    /// Swaps in new audio, fading out the current buffer.
+    /// Returns the old onsets and any buffer from an unfinished fade, to be freed.
+    pub fn receive(&mut self, sample: LoadedSample) -> LoadedSample {
+        let mut retired = self.set(sample);
+        self.fade = 0.0;
+        retired.frames = std::mem::replace(&mut self.previous, retired.frames);
+        retired
+    }
+
+    /// Output of a grain reading from this slot, including any crossfade
+    pub fn frame(&self, grain: &Grain, envelope: &EnvelopeTable) -> StereoFrame {
+        let mut frame = if self.samples.is_empty() {
+            StereoFrame(0.0, 0.0)
+        } else {
+            grain.frame(&self.samples, envelope)
+        };
+
+        // Equal power crossfade from the previous buffer
+        if self.fading() {
+            frame = frame.scale((self.fade * FRAC_PI_2).sin());
+            frame += grain
+                .frame(&self.previous, envelope)
+                .scale((self.fade * FRAC_PI_2).cos());
+        }
+        frame
+    }
+
+    fn fading(&self) -> bool {
+        self.fade < 1.0 && !self.previous.is_empty()
+    }
+
+    /// Moves any crossfade on by a sample, returning the old buffer once it's faded out
+    pub fn advance(&mut self) -> Option<Vec<StereoFrame>> {
+        if !self.fading() {
+            return None;
+        }
+        self.fade += (Self::CROSSFADE_LENGTH as f32).recip();
+        if self.fade >= 1.0 {
+            Some(std::mem::take(&mut self.previous))
+        } else {
+            None
+        }
+    }
+}
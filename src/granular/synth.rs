@@ -0,0 +1,206 @@
+use crate::dsp::{read_interpolated, Interpolation, StereoFrame, SAMPLE_RATE};
+use crate::granular::loader::{self, LoadError, LoadedSample};
+use crate::granular::onset;
+use rand::Rng;
+use rand_core::SeedableRng;
+use rand_pcg::Pcg64Mcg;
+use std::f64::consts::TAU;
+use std::path::PathBuf;
+
+/// Built-in generators that can fill a slot instead of a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Saw,
+    Noise,
+    Fm,        // A sine carrier with one sine modulator
+    Wavetable, // A single cycle read from a file
+}
+
+impl Waveform {
+    pub const ALL: [Waveform; 5] = [
+        Waveform::Sine,
+        Waveform::Saw,
+        Waveform::Noise,
+        Waveform::Fm,
+        Waveform::Wavetable,
+    ];
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SynthParams {
+    pub waveform: Waveform,
+    pub frequency: f32, // In hz
+    pub length: f32,    // In seconds, rounded to whole cycles
+    pub fm_ratio: f32,  // Modulator frequency as a multiple of the carrier, snapped to a fraction
+    pub fm_index: f32,  // Modulation depth, in radians
+    /// The whole file is read as one cycle
+    pub wavetable: PathBuf,
+}
+
+impl Default for SynthParams {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::Sine,
+            frequency: 220.0,
+            length: 2.0,
+            fm_ratio: 2.0,
+            fm_index: 2.0,
+            wavetable: PathBuf::new(),
+        }
+    }
+}
+
+/// Peak level of generated audio, leaving headroom for overlapping grains
+const AMPLITUDE: f32 = 0.5;
+/// Noise is seeded, so regenerating a slot gives the same buffer
+const NOISE_SEED: u64 = 123123123;
+/// FM ratios snap to fractions with at most this denominator,
+/// which is how many carrier cycles it takes for the modulator to line up again
+const MAX_DENOMINATOR: u32 = 8;
+
+/// Fills a buffer from a generator. This can block while a wavetable loads,
+/// so it runs on the loader thread.
+pub fn generate(params: &SynthParams) -> Result<LoadedSample, LoadError> {
+    let frequency = params.frequency.max(1.0) as f64;
+    let (fm_ratio, denominator) = snap_ratio(params.fm_ratio as f64);
+    let period = match params.waveform {
+        Waveform::Fm => denominator,
+        _ => 1.0,
+    };
+    // Whole periods, so grains reading across the end of the buffer don't click
+    let cycles = (params.length as f64 * frequency / period).round().max(1.0) * period;
+    let len = (cycles / frequency * SAMPLE_RATE as f64).round() as usize;
+    let increment = frequency / SAMPLE_RATE as f64;
+
+    let (table, channels) = match params.waveform {
+        Waveform::Wavetable => {
+            let sample = loader::load(&params.wavetable)?;
+            (sample.frames, sample.channels)
+        }
+        _ => (vec![], 1),
+    };
+    let mut rng = Pcg64Mcg::seed_from_u64(NOISE_SEED);
+
+    let frames: Vec<StereoFrame> = (0..len)
+        .map(|i| {
+            let phase = (i as f64 * increment).fract();
+            let value = match params.waveform {
+                Waveform::Sine => (TAU * phase).sin() as f32,
+                Waveform::Saw => (2.0 * phase - 1.0 - poly_blep(phase, increment)) as f32,
+                Waveform::Noise => rng.random_range(-1.0..=1.0),
+                Waveform::Fm => {
+                    let modulator = (TAU * (i as f64 * increment * fm_ratio).fract()).sin();
+                    (TAU * phase + params.fm_index as f64 * modulator).sin() as f32
+                }
+                Waveform::Wavetable => {
+                    let position = phase * table.len() as f64;
+                    let frame = read_interpolated(&table, position, Interpolation::Cubic);
+                    return frame.scale(AMPLITUDE);
+                }
+            };
+            StereoFrame(value, value).scale(AMPLITUDE)
+        })
+        .collect();
+
+    Ok(LoadedSample {
+        onsets: onset::detect(&frames),
+        frames,
+        sample_rate: SAMPLE_RATE,
+        channels,
+    })
+}
+
+/// The nearest fraction to `ratio` with a denominator up to `MAX_DENOMINATOR`,
+/// as the snapped ratio and its denominator. Smaller denominators win ties.
+fn snap_ratio(ratio: f64) -> (f64, f64) {
+    (1..=MAX_DENOMINATOR)
+        .map(|denominator| {
+            let denominator = denominator as f64;
+            ((ratio * denominator).round() / denominator, denominator)
+        })
+        .min_by(|a, b| (a.0 - ratio).abs().total_cmp(&(b.0 - ratio).abs()))
+        .unwrap_or((ratio.round(), 1.0))
+}
+
+/// Smooths the saw's reset, which would otherwise alias
+fn poly_blep(phase: f64, increment: f64) -> f64 {
+    if phase < increment {
+        let t = phase / increment;
+        2.0 * t - t * t - 1.0
+    } else if phase > 1.0 - increment {
+        let t = (phase - 1.0) / increment;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(waveform: Waveform, frequency: f32, length: f32) -> SynthParams {
+        SynthParams {
+            waveform,
+            frequency,
+            length,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sine_fills_whole_cycles() {
+        let sample = generate(&params(Waveform::Sine, 220.0, 2.0)).unwrap();
+        assert_eq!(sample.frames.len(), 2 * SAMPLE_RATE as usize);
+        let peak = sample.frames.iter().map(|f| f.0.abs()).fold(0.0, f32::max);
+        assert!((peak - AMPLITUDE).abs() < 1e-3);
+    }
+
+    #[test]
+    fn length_rounds_to_cycles() {
+        // 2.6 cycles rounds to 3, at 440 samples each
+        let sample = generate(&params(Waveform::Saw, 100.0, 0.026)).unwrap();
+        assert_eq!(sample.frames.len(), 3 * 440);
+    }
+
+    #[test]
+    fn noise_is_repeatable() {
+        let noise = params(Waveform::Noise, 100.0, 0.1);
+        let a = generate(&noise).unwrap().frames;
+        let b = generate(&noise).unwrap().frames;
+        assert!(a.iter().zip(&b).all(|(a, b)| a.0 == b.0 && a.1 == b.1));
+    }
+
+    #[test]
+    fn ratios_snap_to_small_fractions() {
+        assert_eq!(snap_ratio(2.0), (2.0, 1.0));
+        assert_eq!(snap_ratio(1.5), (1.5, 2.0));
+        assert_eq!(snap_ratio(1.34), (4.0 / 3.0, 3.0));
+    }
+
+    #[test]
+    fn fm_fills_whole_periods() {
+        // A 3/2 ratio repeats every 2 carrier cycles, so 3.1 cycles rounds up to 4
+        let fm = SynthParams {
+            fm_ratio: 1.5,
+            ..params(Waveform::Fm, 100.0, 0.031)
+        };
+        let frames = generate(&fm).unwrap().frames;
+        assert_eq!(frames.len(), 4 * 440);
+        let (first, second) = frames.split_at(2 * 440);
+        assert!(first
+            .iter()
+            .zip(second)
+            .all(|(a, b)| (a.0 - b.0).abs() < 1e-4));
+    }
+
+    #[test]
+    fn missing_wavetable_fails() {
+        let wavetable = SynthParams {
+            wavetable: PathBuf::from("does/not/exist.wav"),
+            ..params(Waveform::Wavetable, 100.0, 1.0)
+        };
+        assert!(matches!(generate(&wavetable), Err(LoadError::Io(..))));
+    }
+}
@@ -0,0 +1,290 @@
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// A scale from a Scala .scl file
+#[derive(Debug, Clone)]
+pub struct ScalaScale {
+    pub description: String,
+    /// Degrees 1 to n in cents, the last degree is the period, usually an octave
+    pub cents: Vec<f32>,
+}
+
+/// A keyboard mapping from a Scala .kbm file
+#[derive(Debug, Clone)]
+pub struct KeyboardMap {
+    pub first_note: i32,
+    pub last_note: i32,
+    pub middle_note: i32, // Key which plays degree 0 of the scale
+    pub octave_degree: usize,
+    /// The scale degree each key in a repeat of the map plays, `None` if unmapped.
+    /// An empty map is a linear mapping, one key per degree.
+    pub map: Vec<Option<usize>>,
+}
+
+#[derive(Debug)]
+pub enum TuningError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+}
+
+impl Display for TuningError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TuningError::Io(path, e) => write!(f, "Couldn't open {}: {e}", path.display()),
+            TuningError::Parse(path, e) => write!(f, "Couldn't parse {}: {e}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for TuningError {}
+
+/// Lines of a Scala file, without comments. Blank lines are kept, as a
+/// description can be empty.
+fn lines(path: &Path) -> Result<Vec<String>, TuningError> {
+    let text = std::fs::read_to_string(path).map_err(|e| TuningError::Io(path.to_path_buf(), e))?;
+    Ok(text
+        .lines()
+        .filter(|line| !line.starts_with('!'))
+        .map(|line| line.trim().to_string())
+        .collect())
+}
+
+/// A pitch line is cents if it contains a '.', otherwise a ratio like 3/2 or 2
+fn parse_pitch(line: &str) -> Option<f32> {
+    let value = line.split_whitespace().next()?;
+    if value.contains('.') {
+        return value.parse().ok();
+    }
+    let (numerator, denominator) = value.split_once('/').unwrap_or((value, "1"));
+    let ratio = numerator.parse::<f64>().ok()? / denominator.parse::<f64>().ok()?;
+    (ratio > 0.0).then(|| (1200.0 * ratio.log2()) as f32)
+}
+
+pub fn load_scl(path: &Path) -> Result<ScalaScale, TuningError> {
+    let error = |e: &str| TuningError::Parse(path.to_path_buf(), e.to_string());
+    let lines = lines(path)?;
+    let mut lines = lines.iter();
+    let description = lines.next().ok_or_else(|| error("missing description"))?;
+    let count = lines
+        .next()
+        .and_then(|line| line.split_whitespace().next()?.parse::<usize>().ok())
+        .ok_or_else(|| error("missing note count"))?;
+
+    let cents = lines
+        .filter(|line| !line.is_empty())
+        .take(count)
+        .map(|line| parse_pitch(line).ok_or_else(|| error(&format!("bad pitch '{line}'"))))
+        .collect::<Result<Vec<_>, _>>()?;
+    if cents.len() != count || count == 0 {
+        return Err(error("wrong number of pitches"));
+    }
+
+    Ok(ScalaScale {
+        description: description.clone(),
+        cents,
+    })
+}
+
+pub fn load_kbm(path: &Path) -> Result<KeyboardMap, TuningError> {
+    let error = |e: &str| TuningError::Parse(path.to_path_buf(), e.to_string());
+    let lines = lines(path)?;
+    let mut values = lines
+        .iter()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.split_whitespace().next().unwrap_or(""));
+
+    // Map size, first, last and middle notes, reference note and frequency, then octave degree
+    let mut header = [0.0; 7];
+    for value in header.iter_mut() {
+        *value = values
+            .next()
+            .and_then(|v| v.parse::<f64>().ok())
+            .ok_or_else(|| error("incomplete header"))?;
+    }
+    let size = header[0] as usize;
+    let map = values
+        .take(size)
+        .map(|v| match v {
+            "x" => Ok(None),
+            v => v
+                .parse()
+                .map(Some)
+                .map_err(|_| error(&format!("bad key '{v}'"))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if map.len() != size {
+        return Err(error("wrong number of keys"));
+    }
+
+    Ok(KeyboardMap {
+        first_note: header[1] as i32,
+        last_note: header[2] as i32,
+        middle_note: header[3] as i32,
+        octave_degree: header[6] as usize,
+        map,
+    })
+}
+
+/// Transpositions for a range of keys, worked out up front so they can be
+/// copied to the audio thread without allocating
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TuningTable {
+    semitones: [f32; TuningTable::KEYS],
+}
+
+impl TuningTable {
+    /// Keys from -64 to 63 relative to the middle note
+    pub const KEYS: usize = 128;
+    const MIDDLE: i32 = 64;
+
+    /// Keys play degrees of the scale, counted from the middle note which plays
+    /// the sample at its own pitch. Keys left unmapped, or outside the map's range,
+    /// play the nearest mapped key below them.
+    pub fn new(scale: &ScalaScale, keyboard: Option<&KeyboardMap>) -> Self {
+        let degree_cents = |degree: usize| {
+            let period = scale.cents[scale.cents.len() - 1];
+            let periods = (degree / scale.cents.len()) as f32;
+            match degree % scale.cents.len() {
+                0 => periods * period,
+                step => periods * period + scale.cents[step - 1],
+            }
+        };
+
+        // One key per degree
+        let linear = |key: i32| {
+            let count = scale.cents.len() as i32;
+            let periods = key.div_euclid(count) as f32;
+            periods * degree_cents(count as usize) + degree_cents(key.rem_euclid(count) as usize)
+        };
+
+        let key_cents = |key: i32| -> Option<f32> {
+            let Some(keyboard) = keyboard else {
+                return Some(linear(key));
+            };
+            let note = keyboard.middle_note + key;
+            if note < keyboard.first_note || note > keyboard.last_note {
+                return None;
+            }
+            if keyboard.map.is_empty() {
+                return Some(linear(key));
+            }
+            let size = keyboard.map.len() as i32;
+            let degree = keyboard.map[key.rem_euclid(size) as usize]?;
+            let repeat = degree_cents(keyboard.octave_degree);
+            Some(key.div_euclid(size) as f32 * repeat + degree_cents(degree))
+        };
+
+        let cents: Vec<Option<f32>> = (0..Self::KEYS as i32)
+            .map(|i| key_cents(i - Self::MIDDLE))
+            .collect();
+        let mut semitones = [0.0; Self::KEYS];
+        let first_mapped = cents.iter().flatten().next().copied().unwrap_or(0.0);
+        let mut last = first_mapped;
+        for (semitone, cents) in semitones.iter_mut().zip(&cents) {
+            last = cents.unwrap_or(last);
+            *semitone = last / 100.0;
+        }
+        Self { semitones }
+    }
+
+    /// Transposition in semitones for a key, relative to the middle note
+    pub fn semitones(&self, key: i32) -> f32 {
+        let index = (key + Self::MIDDLE).clamp(0, Self::KEYS as i32 - 1);
+        self.semitones[index as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory for one test's files, removed when the test ends, even if it fails
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(test: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("tuning_{}_{test}", std::process::id()));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn file(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn loads_cents_and_ratios() {
+        let dir = TempDir::new("scl");
+        let path = dir.file(
+            "tuning.scl",
+            "! test.scl\nTest scale\n 3\n!\n200.0\n3/2\n2\n",
+        );
+        let scale = load_scl(&path).unwrap();
+        assert_eq!(scale.description, "Test scale");
+        assert_eq!(scale.cents.len(), 3);
+        assert!((scale.cents[0] - 200.0).abs() < 1e-3);
+        assert!((scale.cents[1] - 701.955).abs() < 1e-3);
+        assert!((scale.cents[2] - 1200.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn rejects_bad_scales() {
+        let dir = TempDir::new("bad_scl");
+        let path = dir.file("bad.scl", "Bad\n2\n100.0\nabc\n");
+        assert!(matches!(load_scl(&path), Err(TuningError::Parse(..))));
+        let path = dir.file("short.scl", "Short\n3\n100.0\n");
+        assert!(matches!(load_scl(&path), Err(TuningError::Parse(..))));
+        let path = dir.0.join("missing.scl");
+        assert!(matches!(load_scl(&path), Err(TuningError::Io(..))));
+    }
+
+    #[test]
+    fn loads_keyboard_maps() {
+        let dir = TempDir::new("kbm");
+        let path = dir.file(
+            "map.kbm",
+            "! map.kbm\n3\n0\n127\n60\n60\n440.0\n3\n! keys\n0\nx\n2\n",
+        );
+        let keyboard = load_kbm(&path).unwrap();
+        assert_eq!(keyboard.first_note, 0);
+        assert_eq!(keyboard.last_note, 127);
+        assert_eq!(keyboard.middle_note, 60);
+        assert_eq!(keyboard.octave_degree, 3);
+        assert_eq!(keyboard.map, vec![Some(0), None, Some(2)]);
+    }
+
+    #[test]
+    fn table_follows_the_map() {
+        let scale = ScalaScale {
+            description: String::new(),
+            cents: vec![200.0, 700.0, 1200.0],
+        };
+        let keyboard = KeyboardMap {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            octave_degree: 3,
+            map: vec![Some(0), None, Some(2)],
+        };
+        let table = TuningTable::new(&scale, Some(&keyboard));
+        assert_eq!(table.semitones(0), 0.0);
+        // Unmapped keys hold the key below
+        assert_eq!(table.semitones(1), 0.0);
+        assert_eq!(table.semitones(2), 7.0);
+        assert_eq!(table.semitones(3), 12.0);
+        assert_eq!(table.semitones(-1), -5.0);
+
+        let linear = TuningTable::new(&scale, None);
+        assert_eq!(linear.semitones(1), 2.0);
+        assert_eq!(linear.semitones(4), 14.0);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::granular::grain::{EnvelopeTable, Grain};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StealMode {
+    Oldest,   // Replace the grain that has played the longest
+    Quietest, // Replace the grain with the lowest envelope level
+    Refuse,   // Drop new grains until a voice is free
+}
+
+/// A fixed set of grain voices, allocated once so spawning never allocates
+#[derive(Debug)]
+pub struct VoicePool {
+    voices: Vec<Grain>,
+    polyphony: usize,
+}
+
+impl VoicePool {
+    /// Upper bound for polyphony, and the number of voices allocated
+    pub const MAX_VOICES: usize = 256;
+
+    pub fn new(polyphony: usize) -> Self {
+        Self {
+            voices: (0..Self::MAX_VOICES).map(|_| Grain::finished()).collect(),
+            polyphony: polyphony.clamp(1, Self::MAX_VOICES),
+        }
+    }
+
+    /// Voices above a lowered limit are left to finish, rather than cut off
+    pub fn set_polyphony(&mut self, polyphony: usize) {
+        self.polyphony = polyphony.clamp(1, Self::MAX_VOICES);
+    }
+
+    pub fn active(&self) -> usize {
+        self.voices.iter().filter(|grain| !grain.finished).count()
+    }
+
+    /// Starts a grain in a free voice, or steals one if all are in use.
+    /// Returns false if the grain was refused.
+    pub fn spawn(&mut self, grain: Grain, steal: StealMode, envelope: &EnvelopeTable) -> bool {
+        let slot = if self.active() < self.polyphony {
+            self.voices.iter().position(|grain| grain.finished)
+        } else {
+            let playing = self
+                .voices
+                .iter()
+                .enumerate()
+                .filter(|(_, grain)| !grain.finished);
+            match steal {
+                StealMode::Oldest => playing.max_by_key(|(_, grain)| grain.age()).map(|(i, _)| i),
+                StealMode::Quietest => playing
+                    .min_by(|(_, a), (_, b)| a.env(envelope).total_cmp(&b.env(envelope)))
+                    .map(|(i, _)| i),
+                StealMode::Refuse => None,
+            }
+        };
+
+        if let Some(i) = slot {
+            self.voices[i] = grain;
+        }
+        slot.is_some()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Grain> {
+        self.voices.iter_mut().filter(|grain| !grain.finished)
+    }
+}
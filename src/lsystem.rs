@@ -2,7 +2,6 @@ use eframe::emath::Pos2;
 use eframe::epaint::pos2;
 use std::collections::HashMap;
 use std::f32::consts::PI;
-use egui::TextBuffer;
 use rand::distr::weighted::WeightedIndex;
 use rand::prelude::Distribution;
 use rand::rng;
@@ -14,6 +13,7 @@ pub struct LSystem {
     pub results_aux: Vec<String>, // Used to recompute plant while still being readable
     pub iterations: usize,
     pub current_iteration: usize,
+    #[allow(private_interfaces)]
     pub rules: HashMap<char, Rule>,
 }
 
@@ -197,6 +197,11 @@ impl Turtle {
         self.angle
     }
 
+    // Number of branches the turtle is currently inside
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
     pub fn rotate(&mut self, angle: f32) {
         self.angle += angle.to_radians();
         let decrease = (self.base_width - self.min_width) * self.width_falloff * 0.25;
@@ -3,23 +3,24 @@ mod dsp;
 mod granular;
 mod lsystem;
 mod plant;
+mod resample;
 mod saturation;
 mod ui;
 mod filters;
 
 use crate::delay::StereoDelay;
-use crate::dsp::{interleave, StereoFrame};
+use crate::dsp::{interleave, StereoFrame, SAMPLE_RATE};
 use crate::granular::GranularEngine;
-use crate::lsystem::LSystem;
 use crate::ui::{DelayUi, GranularUi, LSystemUi};
 use eframe::epaint::FontFamily;
-use egui::{CentralPanel, Color32, Context, Id, RichText, SidePanel, TopBottomPanel, Visuals};
+use egui::{
+    CentralPanel, Color32, Context, Id, RichText, ScrollArea, SidePanel, TopBottomPanel, Visuals,
+};
 use rodio::buffer::SamplesBuffer;
 use rodio::{OutputStream, Sink};
 use std::path::PathBuf;
 use std::sync::mpsc::channel;
 use std::sync::Arc;
-use crate::filters::LPFilter;
 
 struct App {
     granular_ui: GranularUi,
@@ -75,7 +76,10 @@ impl eframe::App for App {
             .resizable(true)
             .min_height(100.0)
             .max_height(200.0)
-            .show(ctx, |ui| self.granular_ui.ui(ui));
+            .show(ctx, |ui| {
+                // The grain controls are taller than the panel, so they scroll
+                ScrollArea::vertical().show(ui, |ui| self.granular_ui.ui(ui));
+            });
 
         SidePanel::left(Id::new("delay_controls"))
             .resizable(true)
@@ -113,6 +117,8 @@ fn main() -> eframe::Result {
     let (delay_send, delay_receive) = channel();
     let (fb_send, fb_receive) = channel();
     let (seq_send, seq_receive) = channel();
+    let (status_send, status_receive) = channel();
+    let (record_send, record_receive) = channel();
 
     // Init granular engine
     let mut granny = GranularEngine::new(
@@ -120,11 +126,22 @@ fn main() -> eframe::Result {
         param_receive,
         gate_receive,
         seq_receive,
+        record_receive,
+        status_send,
     );
     granny.init();
     let sample_len = granny.buffer_size();
-
-    let mut delay = StereoDelay::new(0.5, 0.5, 44000, 0.8, 0.5, delay_receive, fb_receive);
+    let scan_position = granny.scan_position();
+
+    let mut delay = StereoDelay::new(
+        0.5,
+        0.5,
+        SAMPLE_RATE as usize,
+        0.8,
+        0.5,
+        delay_receive,
+        fb_receive,
+    );
 
     let (_stream, stream_handle) = OutputStream::try_default().unwrap();
     let sink = Sink::try_new(&stream_handle).unwrap();
@@ -142,15 +159,23 @@ fn main() -> eframe::Result {
 
             granny.process_block(buffer.as_mut_slice());
             delay.process_block(buffer.as_mut_slice());
+            granny.record(buffer.as_slice());
             let output: Vec<f32> = interleave(buffer.as_slice());
 
             // Play the output buffer
-            sink.append(SamplesBuffer::new(2, 44000, output));
+            sink.append(SamplesBuffer::new(2, SAMPLE_RATE, output));
         }
     });
 
     // Create Ui widgets
-    let granular_ui = GranularUi::new(param_send, gate_send, sample_len);
+    let granular_ui = GranularUi::new(
+        param_send,
+        gate_send,
+        record_send,
+        status_receive,
+        scan_position,
+        sample_len,
+    );
     let delay_ui = DelayUi::new(delay_send, fb_send);
     let lsystem_ui = LSystemUi::new(seq_send);
 
@@ -5,6 +5,7 @@ pub struct Plant {
     pub system: LSystem,
     pub branch_colour: Color32,
     pub leaf_colours: Vec<Color32>,
+    #[allow(dead_code)]
     pub name: String,
 }
 
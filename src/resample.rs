@@ -0,0 +1,76 @@
+use crate::dsp::StereoFrame;
+use rubato::{
+    Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+};
+
+const CHUNK_SIZE: usize = 1024;
+
+/// Converts a whole buffer between sample rates with band limited sinc interpolation.
+/// Intended for use when loading audio, not in real time.
+pub fn resample(frames: &[StereoFrame], from: u32, to: u32) -> Vec<StereoFrame> {
+    if from == to || frames.is_empty() {
+        return frames.to_vec();
+    }
+
+    let params = SincInterpolationParameters {
+        sinc_len: 256,
+        f_cutoff: 0.95,
+        oversampling_factor: 128,
+        interpolation: SincInterpolationType::Cubic,
+        window: WindowFunction::BlackmanHarris2,
+    };
+    let ratio = to as f64 / from as f64;
+    let mut resampler = SincFixedIn::<f32>::new(ratio, 1.0, params, CHUNK_SIZE, 2)
+        .expect("Invalid resampler settings");
+
+    let left: Vec<f32> = frames.iter().map(|frame| frame.0).collect();
+    let right: Vec<f32> = frames.iter().map(|frame| frame.1).collect();
+
+    let expected = (frames.len() as f64 * ratio).ceil() as usize;
+    let mut out = [Vec::with_capacity(expected), Vec::with_capacity(expected)];
+
+    for (l, r) in left.chunks(CHUNK_SIZE).zip(right.chunks(CHUNK_SIZE)) {
+        let chunk = if l.len() == CHUNK_SIZE {
+            resampler.process(&[l, r], None)
+        } else {
+            resampler.process_partial(Some(&[l, r]), None)
+        }
+        .expect("Failed to resample chunk");
+        out[0].extend_from_slice(&chunk[0]);
+        out[1].extend_from_slice(&chunk[1]);
+    }
+
+    // The resampler starts its sinc window half a filter length before the first frame,
+    // so the output is already aligned, but the end is held back until flushed with silence
+    while out[0].len() < expected {
+        let chunk = resampler
+            .process_partial::<&[f32]>(None, None)
+            .expect("Failed to flush resampler");
+        out[0].extend_from_slice(&chunk[0]);
+        out[1].extend_from_slice(&chunk[1]);
+    }
+
+    out[0]
+        .iter()
+        .zip(&out[1])
+        .take(expected)
+        .map(|(l, r)| StereoFrame(*l, *r))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn impulse_stays_in_place() {
+        let mut frames = vec![StereoFrame(0.0, 0.0); 4800];
+        frames[480] = StereoFrame(1.0, 1.0);
+        let out = resample(&frames, 48000, 44000);
+        assert_eq!(out.len(), 4400);
+        let peak = (0..out.len())
+            .max_by(|&a, &b| out[a].0.abs().total_cmp(&out[b].0.abs()))
+            .unwrap();
+        assert_eq!(peak, 440);
+    }
+}
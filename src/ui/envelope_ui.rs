@@ -0,0 +1,130 @@
+use crate::granular::grain::{breakpoints, Breakpoint, EnvelopeTable};
+use egui::{pos2, Color32, Pos2, Rect, Sense, Shape, Stroke, Ui, Vec2};
+
+/// Plot for drawing a custom grain envelope.
+/// Drag points to move them, double click to add one, right click to remove one,
+/// and scroll over a segment to change its tension.
+#[derive(Debug, Default)]
+pub struct EnvelopeEditor {
+    dragging: Option<usize>, // Index of the point being dragged
+}
+
+impl EnvelopeEditor {
+    const SIZE: Vec2 = Vec2::new(240.0, 80.0);
+    const POINT_RADIUS: f32 = 4.0;
+    const MAX_TENSION: f32 = 8.0;
+    const TENSION_SPEED: f32 = 0.02; // Tension change per point of scrolling
+    const CURVE_RESOLUTION: usize = 96;
+
+    /// Returns true if the points were changed
+    pub fn ui(&mut self, ui: &mut Ui, points: &mut Vec<Breakpoint>) -> bool {
+        let (response, painter) = ui.allocate_painter(Self::SIZE, Sense::click_and_drag());
+        let rect = response.rect;
+        let mut changed = false;
+
+        let hovered = response
+            .hover_pos()
+            .and_then(|pos| Self::point_at(rect, points, pos));
+
+        if response.drag_started() {
+            self.dragging = response
+                .interact_pointer_pos()
+                .and_then(|pos| Self::point_at(rect, points, pos));
+        }
+        if response.drag_stopped() {
+            self.dragging = None;
+        }
+
+        if let (Some(i), Some(pos)) = (self.dragging, response.interact_pointer_pos()) {
+            let (t, level) = Self::from_screen(rect, pos);
+            let last = points.len() - 1;
+            // The ends stay at the start and end of the grain, other points stay between neighbours
+            if i != 0 && i != last {
+                points[i].t = t.clamp(points[i - 1].t, points[i + 1].t);
+            }
+            points[i].level = level;
+            changed = true;
+        }
+
+        if response.double_clicked() && hovered.is_none() {
+            if let Some(pos) = response.interact_pointer_pos() {
+                if points.len() < EnvelopeTable::MAX_POINTS {
+                    let (t, level) = Self::from_screen(rect, pos);
+                    let index = points.partition_point(|p| p.t < t);
+                    points.insert(index, Breakpoint::new(t, level, 0.0));
+                    changed = true;
+                }
+            }
+        }
+
+        if response.secondary_clicked() {
+            if let Some(i) = hovered.filter(|i| *i != 0 && *i != points.len() - 1) {
+                points.remove(i);
+                changed = true;
+            }
+        }
+
+        if let Some(pos) = response.hover_pos() {
+            // Taken from the input, so the panel holding the editor doesn't scroll as well
+            let scroll = ui.input_mut(|i| {
+                i.smooth_scroll_delta = Vec2::ZERO;
+                i.raw_scroll_delta.y
+            });
+            if scroll != 0.0 {
+                // The segment under the cursor, by the point it leads into
+                let (t, _) = Self::from_screen(rect, pos);
+                let index = points
+                    .partition_point(|p| p.t < t)
+                    .clamp(1, points.len() - 1);
+                points[index].tension = (points[index].tension + scroll * Self::TENSION_SPEED)
+                    .clamp(-Self::MAX_TENSION, Self::MAX_TENSION);
+                changed = true;
+            }
+        }
+
+        // Draw the curve and its points
+        painter.rect_filled(rect, 5.0, ui.visuals().extreme_bg_color);
+        let curve = (0..=Self::CURVE_RESOLUTION)
+            .map(|i| {
+                let t = i as f32 / Self::CURVE_RESOLUTION as f32;
+                Self::to_screen(rect, t, breakpoints(t, points))
+            })
+            .collect();
+        painter.add(Shape::line(curve, Stroke::new(1.5, Color32::DARK_GREEN)));
+
+        for (i, point) in points.iter().enumerate() {
+            let colour = if Some(i) == hovered || Some(i) == self.dragging {
+                Color32::WHITE
+            } else {
+                Color32::LIGHT_GREEN
+            };
+            painter.circle_filled(
+                Self::to_screen(rect, point.t, point.level),
+                Self::POINT_RADIUS,
+                colour,
+            );
+        }
+
+        changed
+    }
+
+    fn to_screen(rect: Rect, t: f32, level: f32) -> Pos2 {
+        pos2(
+            rect.left() + t * rect.width(),
+            rect.bottom() - level * rect.height(),
+        )
+    }
+
+    fn from_screen(rect: Rect, pos: Pos2) -> (f32, f32) {
+        (
+            ((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0),
+            ((rect.bottom() - pos.y) / rect.height()).clamp(0.0, 1.0),
+        )
+    }
+
+    fn point_at(rect: Rect, points: &[Breakpoint], pos: Pos2) -> Option<usize> {
+        points.iter().position(|p| {
+            Self::to_screen(rect, p.t, p.level).distance(pos) <= 2.0 * Self::POINT_RADIUS
+        })
+    }
+}
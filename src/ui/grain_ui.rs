@@ -1,50 +1,193 @@
-use crate::granular::grain::EnvelopeMode;
-use crate::granular::GranularParams;
+use crate::dsp::{Interpolation, PanLaw, StereoFrame, SAMPLE_RATE};
+use crate::filters::FilterMode;
+use crate::granular::grain::{Direction, EnvelopeMode};
+use crate::granular::loader::SlotSource;
+use crate::granular::onset::OnsetMode;
+use crate::granular::record::RecordMessage;
+use crate::granular::scale::{Chord, Scale, NOTE_NAMES};
+use crate::granular::scan::{LoopMode, Stretch};
+use crate::granular::sequencer::{LeafMapping, Scheduling, SlotMapping};
+use crate::granular::synth::SynthParams;
+use crate::granular::tuning::{self, TuningError, TuningTable};
+use crate::granular::voices::{StealMode, VoicePool};
+use crate::granular::{GainCompensation, GranularEngine, GranularParams, GranularStatus};
+use crate::ui::envelope_ui::EnvelopeEditor;
+use crate::ui::synth_ui::synth_panel;
 use crate::ui::{call_on_change, send_params};
-use egui::{ComboBox, Slider, Ui, Widget};
-use std::sync::mpsc::Sender;
+use egui::{
+    Button, Checkbox, Color32, ComboBox, DragValue, ProgressBar, Slider, TextEdit, Ui, Widget,
+};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+
+/// Ui state for one sample slot, sent as a `SlotSource` when loaded or generated
+#[derive(Debug, Default)]
+struct SlotUi {
+    path: String, // Contents of the file path box
+    synth: bool,  // Filled by a generator instead of a file
+    synth_params: SynthParams,
+    wavetable: String,
+    error: Option<String>, // Why the last load failed
+}
+
+impl SlotUi {
+    fn new(source: &SlotSource) -> Self {
+        match source {
+            SlotSource::File(path) => Self {
+                path: path.display().to_string(),
+                ..Default::default()
+            },
+            SlotSource::Synth(params) => Self {
+                synth: true,
+                synth_params: params.clone(),
+                wavetable: params.wavetable.display().to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn source(&self) -> SlotSource {
+        if self.synth {
+            SlotSource::Synth(self.synth_params.clone())
+        } else {
+            SlotSource::File(PathBuf::from(&self.path))
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct GranularUi {
     params: GranularParams,
     gate: bool,
-    buf_len: usize, // The length of the buffer these params operate on
-    sender: Sender<GranularParams>,
+    buf_len: usize, // The length of the first slot's buffer
+    slots: Vec<SlotUi>,
+    onsets: [usize; GranularEngine::MAX_SLOTS], // Number detected in each slot
+    envelope_editor: EnvelopeEditor,
+    sender: Sender<Box<GranularParams>>,
     gate_sender: Sender<bool>,
+    record_sender: Sender<RecordMessage>,
+    record_length: f32, // In seconds
+    record_slot: usize,
+    record_rolling: bool,
+    recording: bool,
+    scl_path: String,
+    kbm_path: String,    // Optional, keys map straight to degrees without one
+    tuning_info: String, // Description of the loaded tuning, or why it failed
+    status_rcvr: Receiver<GranularStatus>,
+    scan_position: Arc<AtomicUsize>,
 }
 
 impl GranularUi {
-    pub fn new(sender: Sender<GranularParams>, gate_sender: Sender<bool>, buf_len: usize) -> Self {
+    pub fn new(
+        sender: Sender<Box<GranularParams>>,
+        gate_sender: Sender<bool>,
+        record_sender: Sender<RecordMessage>,
+        status_rcvr: Receiver<GranularStatus>,
+        scan_position: Arc<AtomicUsize>,
+        buf_len: usize,
+    ) -> Self {
+        let params = GranularParams::default();
         Self {
-            params: Default::default(),
+            slots: params
+                .sources
+                .iter()
+                .map(|source| SlotUi::new(source))
+                .collect(),
+            params,
+            onsets: [0; GranularEngine::MAX_SLOTS],
+            envelope_editor: Default::default(),
             gate: true,
             buf_len,
             sender,
             gate_sender,
+            record_sender,
+            record_length: 4.0,
+            record_slot: 0,
+            record_rolling: false,
+            recording: false,
+            scl_path: String::new(),
+            kbm_path: String::new(),
+            tuning_info: "12-TET".to_string(),
+            status_rcvr,
+            scan_position,
+        }
+    }
+
+    fn update_status(&mut self) {
+        while let Ok(status) = self.status_rcvr.try_recv() {
+            if status.slot == 0 {
+                self.buf_len = status.buffer_len;
+            }
+            self.onsets[status.slot] = status.onsets;
+            self.recording = status.recording;
+            if let Some(state) = self.slots.get_mut(status.slot) {
+                state.error = status.error;
+            }
         }
     }
 
     fn update_params(&self) {
-        send_params(&self.sender, self.params.clone())
+        send_params(&self.sender, Box::new(self.params.clone()))
+    }
+
+    /// Parses the Scala files, which are small enough to read on the Ui thread
+    fn load_tuning(&self) -> Result<(TuningTable, String), TuningError> {
+        let scale = tuning::load_scl(Path::new(&self.scl_path))?;
+        let keyboard = if self.kbm_path.is_empty() {
+            None
+        } else {
+            Some(tuning::load_kbm(Path::new(&self.kbm_path))?)
+        };
+        let table = TuningTable::new(&scale, keyboard.as_ref());
+        Ok((table, scale.description))
+    }
+
+    /// Starts recording the output into the chosen slot, replacing its file
+    fn start_recording(&mut self) {
+        // Allocated here rather than on the audio thread
+        let length = (self.record_length * SAMPLE_RATE as f32) as usize;
+        let buffer = vec![StereoFrame(0.0, 0.0); length.max(1)];
+        send_params(
+            &self.record_sender,
+            RecordMessage::Start {
+                slot: self.record_slot,
+                buffer,
+                rolling: self.record_rolling,
+            },
+        );
+        // Matches the engine, which forgets the slot's source when recording starts
+        self.params.sources[self.record_slot] = Arc::new(SlotSource::File(PathBuf::new()));
+        self.slots[self.record_slot] = SlotUi::default();
+        self.recording = true;
     }
 
     pub fn ui(&mut self, ui: &mut Ui) {
+        self.update_status();
         ui.vertical(|ui| {
             ui.heading("Grain Controls");
             ui.horizontal(|ui| {
-                let start = Slider::new(&mut self.params.start, 0..=(self.buf_len - 1))
+                // The first slot can be empty, which would leave the ranges backwards
+                let last = self.buf_len.saturating_sub(1).max(1);
+                let start = Slider::new(&mut self.params.start, 0..=last)
                     .drag_value_speed(50.0)
                     .text("Start")
                     .ui(ui);
 
-                // Min length of 25ms at 45khz, max of 8 seconds, or the length of the buffer
-                let length = Slider::new(
-                    &mut self.params.grain_length,
-                    1100..=(44000 * 8).min(self.buf_len - 1),
-                )
-                .drag_value_speed(10.0)
-                .text("Length")
-                .ui(ui);
+                // Min length of 25ms, max of 8 seconds, or the length of the buffer
+                let min_length = SAMPLE_RATE as usize / 40;
+                // Time stretch and pulsar scheduling set the length themselves
+                let pulsar = self.params.scheduling == Scheduling::Pulsar;
+                let length = ui.add_enabled(
+                    !self.params.stretch && !pulsar,
+                    Slider::new(
+                        &mut self.params.grain_length,
+                        min_length..=(SAMPLE_RATE as usize * 8).min(last),
+                    )
+                    .drag_value_speed(10.0)
+                    .text("Length"),
+                );
 
                 if ui.button("Scan").clicked() {
                     let state = self.params.scan.unwrap_or(false);
@@ -56,34 +199,663 @@ impl GranularUi {
                     self.params.scan = Some(!state);
                     send_params(
                         &self.sender,
-                        GranularParams {
+                        Box::new(GranularParams {
                             scan: Some(!state),
                             ..self.params.clone()
-                        },
+                        }),
                     );
                 }
 
                 call_on_change(|| self.update_params(), &[start, length]);
             });
 
-            ui.heading("Envelope Controls");
             ui.horizontal(|ui| {
-                let spread = Slider::new(&mut self.params.grain_spread, 500..=self.buf_len)
-                    .drag_value_speed(1.0)
-                    .text("Spread")
+                let rate = ui.add_enabled(
+                    !self.params.stretch,
+                    Slider::new(&mut self.params.scan_rate, -4.0..=4.0)
+                        .drag_value_speed(0.001)
+                        .text("Scan rate"),
+                );
+                if self.params.scan_rate != 0.0 {
+                    ui.label(format!(
+                        "Stretch {:.2}x",
+                        self.params.scan_rate.abs().recip()
+                    ));
+                }
+
+                let loop_start = Slider::new(&mut self.params.loop_start, 0.0..=1.0)
+                    .drag_value_speed(0.001)
+                    .text("Loop in")
+                    .ui(ui);
+                let loop_end = Slider::new(&mut self.params.loop_end, 0.0..=1.0)
+                    .drag_value_speed(0.001)
+                    .text("Loop out")
                     .ui(ui);
 
-                let gain = Slider::new(&mut self.params.gain, 0.0..=2.0)
+                let loop_mode = self.params.loop_mode;
+                ComboBox::from_label("Loop mode")
+                    .selected_text(format!("{:?}", self.params.loop_mode))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.params.loop_mode, LoopMode::Loop, "Loop");
+                        ui.selectable_value(
+                            &mut self.params.loop_mode,
+                            LoopMode::PingPong,
+                            "Ping-pong",
+                        );
+                        ui.selectable_value(
+                            &mut self.params.loop_mode,
+                            LoopMode::OneShot,
+                            "One shot",
+                        );
+                    });
+                if loop_mode != self.params.loop_mode {
+                    self.update_params();
+                }
+
+                // Where grains are spawning from, which moves while scanning
+                let position = self.scan_position.load(Ordering::Relaxed);
+                ProgressBar::new(position as f32 / self.buf_len.max(1) as f32)
+                    .desired_width(160.0)
+                    .text(format!("{:.2} s", position as f32 / SAMPLE_RATE as f32))
+                    .ui(ui);
+                // Only moving while scanning, so there's nothing to redraw otherwise
+                if self.params.scan.unwrap_or(false) || self.params.stretch {
+                    ui.ctx().request_repaint();
+                }
+
+                call_on_change(|| self.update_params(), &[rate, loop_start, loop_end]);
+            });
+
+            ui.horizontal(|ui| {
+                if ui
+                    .selectable_label(self.params.stretch, "Time stretch")
+                    .clicked()
+                {
+                    self.params.stretch = !self.params.stretch;
+                    self.update_params();
+                }
+
+                let factor = Slider::new(&mut self.params.stretch_factor, 1.0..=64.0)
+                    .logarithmic(true)
+                    .drag_value_speed(0.01)
+                    .text("Stretch factor")
+                    .ui(ui);
+
+                if self.params.stretch {
+                    let stretch = Stretch::new(self.params.stretch_factor);
+                    ui.label(format!(
+                        "Grains of {:.2} s at {:.1} Hz",
+                        stretch.grain_length as f32 / SAMPLE_RATE as f32,
+                        stretch.density
+                    ));
+                }
+
+                call_on_change(|| self.update_params(), &[factor]);
+            });
+
+            ui.heading("Sample Slots");
+            for slot in 0..self.slots.len() {
+                let state = &mut self.slots[slot];
+                let mut load = false;
+                // Loading into the slot being recorded would be thrown away
+                let recording = self.recording && slot == self.record_slot;
+                ui.add_enabled_ui(!recording, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Slot {}", slot + 1));
+                        ui.selectable_value(&mut state.synth, false, "File");
+                        ui.selectable_value(&mut state.synth, true, "Synth");
+                        if !state.synth {
+                            TextEdit::singleline(&mut state.path)
+                                .hint_text("Sample path")
+                                .ui(ui);
+                            load = ui.button("Load").clicked();
+                        }
+                        ui.label(format!("{} onsets", self.onsets[slot]));
+                        if let Some(error) = &state.error {
+                            ui.colored_label(Color32::DARK_RED, error);
+                        }
+                    });
+                    if state.synth {
+                        load = synth_panel(ui, slot, &mut state.synth_params, &mut state.wavetable);
+                    }
+                });
+                if load {
+                    self.params.sources[slot] = Arc::new(state.source());
+                    self.update_params();
+                }
+            }
+            ui.horizontal(|ui| {
+                let can_add = self.slots.len() < GranularEngine::MAX_SLOTS;
+                if ui.add_enabled(can_add, Button::new("Add slot")).clicked() {
+                    self.slots.push(SlotUi::default());
+                    self.params
+                        .sources
+                        .push(Arc::new(SlotSource::File(PathBuf::new())));
+                    self.update_params();
+                }
+                // The first slot drives the scan and the start slider, so it always stays
+                let can_remove = self.slots.len() > 1;
+                if ui
+                    .add_enabled(can_remove, Button::new("Remove slot"))
+                    .clicked()
+                {
+                    self.slots.pop();
+                    self.params.sources.pop();
+                    self.update_params();
+                }
+
+                let mapping = self.params.slot_mapping;
+                ComboBox::from_label("Slot from leaf")
+                    .selected_text(format!("{:?}", self.params.slot_mapping))
+                    .show_ui(ui, |ui| {
+                        for mode in SlotMapping::ALL {
+                            ui.selectable_value(
+                                &mut self.params.slot_mapping,
+                                mode,
+                                format!("{mode:?}"),
+                            );
+                        }
+                    });
+                let onset_mode = self.params.onset_mode;
+                ComboBox::from_label("Onsets")
+                    .selected_text(format!("{:?}", self.params.onset_mode))
+                    .show_ui(ui, |ui| {
+                        for mode in OnsetMode::ALL {
+                            ui.selectable_value(
+                                &mut self.params.onset_mode,
+                                mode,
+                                format!("{mode:?}"),
+                            );
+                        }
+                    });
+                if mapping != self.params.slot_mapping || onset_mode != self.params.onset_mode {
+                    self.update_params();
+                }
+            });
+
+            // Resampling the output, after the delay, back into a slot
+            ui.horizontal(|ui| {
+                self.record_slot = self.record_slot.min(self.slots.len() - 1);
+                ui.add_enabled(
+                    !self.recording,
+                    DragValue::from_get_set(|value| {
+                        if let Some(value) = value {
+                            self.record_slot = value as usize - 1;
+                        }
+                        (self.record_slot + 1) as f64
+                    })
+                    .range(1..=self.slots.len())
+                    .prefix("Slot "),
+                );
+                ui.add_enabled(
+                    !self.recording,
+                    Slider::new(&mut self.record_length, 0.5..=30.0)
+                        .text("Length")
+                        .suffix(" s"),
+                );
+                ui.add_enabled(
+                    !self.recording,
+                    Checkbox::new(&mut self.record_rolling, "Rolling"),
+                );
+                if self.recording {
+                    if ui.button("Stop").clicked() {
+                        send_params(&self.record_sender, RecordMessage::Stop);
+                        self.recording = false;
+                    }
+                } else if ui.button("Resample").clicked() {
+                    self.start_recording();
+                }
+            });
+
+            ui.heading("Playback Controls");
+            ui.horizontal(|ui| {
+                let pitch = Slider::new(&mut self.params.pitch, -24.0..=24.0)
+                    .drag_value_speed(0.1)
+                    .text("Pitch")
+                    .suffix(" st")
+                    .ui(ui);
+
+                let rate = ui.add_enabled(
+                    !self.params.stretch,
+                    Slider::new(&mut self.params.rate, 0.25..=4.0)
+                        .logarithmic(true)
+                        .drag_value_speed(0.01)
+                        .text("Rate"),
+                );
+
+                let interpolation = self.params.interpolation;
+                ComboBox::from_label("Interpolation")
+                    .selected_text(format!("{:?}", self.params.interpolation))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut self.params.interpolation,
+                            Interpolation::Linear,
+                            "Linear",
+                        );
+                        ui.selectable_value(
+                            &mut self.params.interpolation,
+                            Interpolation::Cubic,
+                            "Cubic",
+                        );
+                    });
+                let direction = self.params.direction;
+                ComboBox::from_label("Direction")
+                    .selected_text(format!("{:?}", self.params.direction))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut self.params.direction,
+                            Direction::Forward,
+                            "Forward",
+                        );
+                        ui.selectable_value(
+                            &mut self.params.direction,
+                            Direction::Reverse,
+                            "Reverse",
+                        );
+                        ui.selectable_value(
+                            &mut self.params.direction,
+                            Direction::PingPong,
+                            "Ping-pong",
+                        );
+                        ui.selectable_value(
+                            &mut self.params.direction,
+                            Direction::Random,
+                            "Random",
+                        );
+                    });
+
+                let pan_law = self.params.pan_law;
+                ComboBox::from_label("Pan law")
+                    .selected_text(format!("{:?}", self.params.pan_law))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.params.pan_law, PanLaw::Linear, "Linear");
+                        ui.selectable_value(
+                            &mut self.params.pan_law,
+                            PanLaw::ConstantPower,
+                            "Constant power",
+                        );
+                        ui.selectable_value(
+                            &mut self.params.pan_law,
+                            PanLaw::Compromise,
+                            "-4.5 dB",
+                        );
+                    });
+
+                if interpolation != self.params.interpolation
+                    || direction != self.params.direction
+                    || pan_law != self.params.pan_law
+                {
+                    self.update_params();
+                }
+
+                call_on_change(|| self.update_params(), &[pitch, rate]);
+            });
+
+            // Leaves picking pitches in a key
+            ui.horizontal(|ui| {
+                let mapping = self.params.pitch_mapping;
+                ComboBox::from_label("Pitch from leaf")
+                    .selected_text(format!("{:?}", self.params.pitch_mapping))
+                    .show_ui(ui, |ui| {
+                        for mode in LeafMapping::ALL {
+                            ui.selectable_value(
+                                &mut self.params.pitch_mapping,
+                                mode,
+                                format!("{mode:?}"),
+                            );
+                        }
+                    });
+                let range = Slider::new(&mut self.params.pitch_range, 0.0..=36.0)
+                    .drag_value_speed(0.1)
+                    .text("Range")
+                    .suffix(" st")
+                    .ui(ui);
+
+                let root = self.params.root;
+                ComboBox::from_label("Root")
+                    .selected_text(NOTE_NAMES[self.params.root])
+                    .show_ui(ui, |ui| {
+                        for (note, name) in NOTE_NAMES.iter().enumerate() {
+                            ui.selectable_value(&mut self.params.root, note, *name);
+                        }
+                    });
+                // Loaded tunings bring their own scale
+                let scale = self.params.scale;
+                let mut custom_changed = false;
+                ui.add_enabled_ui(self.params.tuning.is_none(), |ui| {
+                    ComboBox::from_label("Scale")
+                        .selected_text(format!("{:?}", self.params.scale))
+                        .show_ui(ui, |ui| {
+                            for scale in Scale::ALL {
+                                ui.selectable_value(
+                                    &mut self.params.scale,
+                                    scale,
+                                    format!("{scale:?}"),
+                                );
+                            }
+                        });
+
+                    if self.params.scale == Scale::Custom {
+                        // Notes named from the root up
+                        for (step, enabled) in self.params.custom_scale.iter_mut().enumerate() {
+                            let name = NOTE_NAMES[(self.params.root + step) % 12];
+                            if ui.selectable_label(*enabled, name).clicked() {
+                                *enabled = !*enabled;
+                                custom_changed = true;
+                            }
+                        }
+                    }
+                });
+
+                if mapping != self.params.pitch_mapping
+                    || root != self.params.root
+                    || scale != self.params.scale
+                    || custom_changed
+                {
+                    self.update_params();
+                }
+                call_on_change(|| self.update_params(), &[range]);
+            });
+
+            ui.horizontal(|ui| {
+                TextEdit::singleline(&mut self.scl_path)
+                    .hint_text("Scala .scl path")
+                    .ui(ui);
+                TextEdit::singleline(&mut self.kbm_path)
+                    .hint_text("Keyboard .kbm path")
+                    .ui(ui);
+                if ui.button("Load tuning").clicked() {
+                    match self.load_tuning() {
+                        Ok((table, description)) => {
+                            self.params.tuning = Some(table);
+                            self.tuning_info = description;
+                            self.update_params();
+                        }
+                        Err(e) => self.tuning_info = e.to_string(),
+                    }
+                }
+                if ui.button("12-TET").clicked() {
+                    self.params.tuning = None;
+                    self.tuning_info = "12-TET".to_string();
+                    self.update_params();
+                }
+                ui.label(&self.tuning_info);
+            });
+
+            ui.heading("Cloud Controls");
+            ui.horizontal(|ui| {
+                let size = Slider::new(&mut self.params.cloud_size, 1..=8)
+                    .text("Grains per event")
+                    .ui(ui);
+
+                let chord = self.params.chord;
+                ComboBox::from_label("Chord")
+                    .selected_text(format!("{:?}", self.params.chord))
+                    .show_ui(ui, |ui| {
+                        for chord in Chord::ALL {
+                            ui.selectable_value(
+                                &mut self.params.chord,
+                                chord,
+                                format!("{chord:?}"),
+                            );
+                        }
+                    });
+                let interval = ui.add_enabled(
+                    self.params.chord == Chord::Stack,
+                    Slider::new(&mut self.params.chord_interval, -12.0..=12.0)
+                        .drag_value_speed(0.1)
+                        .text("Interval")
+                        .suffix(" st"),
+                );
+
+                // Up to 50ms between each grain
+                let offset =
+                    Slider::new(&mut self.params.cloud_offset, 0..=SAMPLE_RATE as usize / 20)
+                        .drag_value_speed(10.0)
+                        .text("Offset")
+                        .ui(ui);
+
+                if chord != self.params.chord {
+                    self.update_params();
+                }
+                call_on_change(|| self.update_params(), &[size, interval, offset]);
+            });
+
+            ui.heading("Jitter Controls");
+            ui.horizontal(|ui| {
+                let start = Slider::new(&mut self.params.start_jitter, 0..=SAMPLE_RATE as usize)
+                    .drag_value_speed(10.0)
+                    .text("Start")
+                    .ui(ui);
+
+                let length = Slider::new(&mut self.params.length_jitter, 0.0..=1.0)
+                    .drag_value_speed(0.01)
+                    .text("Length")
+                    .ui(ui);
+
+                let pitch = Slider::new(&mut self.params.pitch_jitter, 0.0..=12.0)
+                    .drag_value_speed(0.01)
+                    .text("Pitch")
+                    .suffix(" st")
+                    .ui(ui);
+
+                let pan = Slider::new(&mut self.params.pan_jitter, 0.0..=1.0)
+                    .drag_value_speed(0.01)
+                    .text("Pan")
+                    .ui(ui);
+
+                let gain = Slider::new(&mut self.params.gain_jitter, 0.0..=1.0)
                     .drag_value_speed(0.01)
                     .text("Gain")
                     .ui(ui);
 
-                let density = Slider::new(&mut self.params.density, 0.10..=48.00)
+                ui.label("Seed");
+                let seed = DragValue::new(&mut self.params.seed).ui(ui);
+                if ui.button("New seed").clicked() {
+                    self.params.seed = rand::random();
+                    self.update_params();
+                }
+
+                call_on_change(
+                    || self.update_params(),
+                    &[start, length, pitch, pan, gain, seed],
+                );
+            });
+
+            ui.heading("Filter Controls");
+            ui.horizontal(|ui| {
+                let enabled = ui.checkbox(&mut self.params.filter, "Filter");
+
+                let mode = self.params.filter_mode;
+                ComboBox::from_label("Mode")
+                    .selected_text(format!("{:?}", self.params.filter_mode))
+                    .show_ui(ui, |ui| {
+                        for mode in FilterMode::ALL {
+                            ui.selectable_value(
+                                &mut self.params.filter_mode,
+                                mode,
+                                format!("{mode:?}"),
+                            );
+                        }
+                    });
+                let cutoff = Slider::new(&mut self.params.cutoff, 20.0..=20000.0)
+                    .logarithmic(true)
+                    .text("Cutoff")
+                    .suffix(" Hz")
+                    .ui(ui);
+                let resonance = Slider::new(&mut self.params.resonance, 0.5..=12.0)
+                    .logarithmic(true)
+                    .text("Resonance")
+                    .ui(ui);
+
+                if mode != self.params.filter_mode {
+                    self.update_params();
+                }
+                call_on_change(|| self.update_params(), &[enabled, cutoff, resonance]);
+            });
+            ui.horizontal(|ui| {
+                let mapping = self.params.cutoff_mapping;
+                ComboBox::from_label("Cutoff from leaf")
+                    .selected_text(format!("{:?}", self.params.cutoff_mapping))
+                    .show_ui(ui, |ui| {
+                        for mode in LeafMapping::ALL {
+                            ui.selectable_value(
+                                &mut self.params.cutoff_mapping,
+                                mode,
+                                format!("{mode:?}"),
+                            );
+                        }
+                    });
+                let range = Slider::new(&mut self.params.cutoff_range, -6.0..=6.0)
+                    .drag_value_speed(0.01)
+                    .text("Range")
+                    .suffix(" oct")
+                    .ui(ui);
+                let cutoff_jitter = Slider::new(&mut self.params.cutoff_jitter, 0.0..=4.0)
+                    .drag_value_speed(0.01)
+                    .text("Cutoff jitter")
+                    .suffix(" oct")
+                    .ui(ui);
+                let resonance_jitter = Slider::new(&mut self.params.resonance_jitter, 0.0..=1.0)
+                    .drag_value_speed(0.01)
+                    .text("Resonance jitter")
+                    .ui(ui);
+
+                if mapping != self.params.cutoff_mapping {
+                    self.update_params();
+                }
+                call_on_change(
+                    || self.update_params(),
+                    &[range, cutoff_jitter, resonance_jitter],
+                );
+            });
+
+            ui.heading("Envelope Controls");
+            ui.horizontal(|ui| {
+                // Time stretch sets spread and density itself
+                let free = !self.params.stretch;
+                let spread = ui.add_enabled(
+                    free,
+                    Slider::new(&mut self.params.grain_spread, 500..=self.buf_len)
+                        .drag_value_speed(1.0)
+                        .text("Spread"),
+                );
+
+                let gain = Slider::new(&mut self.params.gain, 0.0..=2.0)
                     .drag_value_speed(0.01)
-                    .text("Density")
+                    .text("Gain")
                     .ui(ui);
 
-                call_on_change(|| self.update_params(), &[density, spread, gain])
+                // Pulsar scheduling sets density from the fundamental
+                let pulsar = self.params.scheduling == Scheduling::Pulsar;
+                let density = ui.add_enabled(
+                    free && !pulsar,
+                    Slider::new(&mut self.params.density, 0.10..=48.00)
+                        .drag_value_speed(0.01)
+                        .text("Density"),
+                );
+
+                let polyphony = Slider::new(&mut self.params.polyphony, 1..=VoicePool::MAX_VOICES)
+                    .text("Voices")
+                    .ui(ui);
+
+                let steal_mode = self.params.steal_mode;
+                ComboBox::from_label("Voice stealing")
+                    .selected_text(format!("{:?}", self.params.steal_mode))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut self.params.steal_mode,
+                            StealMode::Oldest,
+                            "Oldest",
+                        );
+                        ui.selectable_value(
+                            &mut self.params.steal_mode,
+                            StealMode::Quietest,
+                            "Quietest",
+                        );
+                        ui.selectable_value(
+                            &mut self.params.steal_mode,
+                            StealMode::Refuse,
+                            "Refuse",
+                        );
+                    });
+                let compensation = self.params.gain_compensation;
+                ComboBox::from_label("Gain compensation")
+                    .selected_text(format!("{:?}", self.params.gain_compensation))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut self.params.gain_compensation,
+                            GainCompensation::Off,
+                            "Off",
+                        );
+                        ui.selectable_value(
+                            &mut self.params.gain_compensation,
+                            GainCompensation::Linear,
+                            "1 / N",
+                        );
+                        ui.selectable_value(
+                            &mut self.params.gain_compensation,
+                            GainCompensation::EqualPower,
+                            "1 / sqrt(N)",
+                        );
+                    });
+
+                if steal_mode != self.params.steal_mode
+                    || compensation != self.params.gain_compensation
+                {
+                    self.update_params();
+                }
+
+                call_on_change(|| self.update_params(), &[density, spread, gain, polyphony])
+            });
+
+            ui.horizontal(|ui| {
+                let scheduling = self.params.scheduling;
+                ComboBox::from_label("Scheduling")
+                    .selected_text(format!("{:?}", self.params.scheduling))
+                    .show_ui(ui, |ui| {
+                        for mode in Scheduling::ALL {
+                            ui.selectable_value(
+                                &mut self.params.scheduling,
+                                mode,
+                                format!("{mode:?}"),
+                            );
+                        }
+                    });
+                let regularity = ui.add_enabled(
+                    self.params.scheduling.random(),
+                    Slider::new(&mut self.params.regularity, 0.0..=1.0)
+                        .drag_value_speed(0.01)
+                        .text("Regularity"),
+                );
+
+                // Audio rate, so the spawn rate is heard as the pitch
+                let pulsar = self.params.scheduling == Scheduling::Pulsar;
+                let fundamental = ui.add_enabled(
+                    pulsar,
+                    Slider::new(&mut self.params.fundamental, 20.0..=2000.0)
+                        .logarithmic(true)
+                        .text("Fundamental")
+                        .suffix(" Hz"),
+                );
+                let duty = ui.add_enabled(
+                    pulsar,
+                    Slider::new(&mut self.params.duty, 0.01..=1.0)
+                        .drag_value_speed(0.01)
+                        .text("Duty"),
+                );
+                if pulsar {
+                    // Shorter grains push the formant of each pulse up
+                    ui.label(format!(
+                        "Formant {:.0} Hz",
+                        self.params.fundamental / self.params.duty
+                    ));
+                }
+
+                if scheduling != self.params.scheduling {
+                    self.update_params();
+                }
+                call_on_change(|| self.update_params(), &[regularity, fundamental, duty]);
             });
             let msg = if self.gate { "Pause" } else { "Play" };
             ui.horizontal(|ui| {
@@ -94,44 +866,51 @@ impl GranularUi {
                         .expect("Failed to send gate");
                 }
 
+                let envelope_mode = self.params.envelope_mode;
                 ComboBox::from_label("Envelope type")
                     .selected_text(format!("{:?}", self.params.envelope_mode))
                     .show_ui(ui, |ui| {
-                        ui.selectable_value(
-                            &mut self.params.envelope_mode,
-                            EnvelopeMode::Smooth,
-                            "Smooth",
-                        );
-                        ui.selectable_value(
-                            &mut self.params.envelope_mode,
-                            EnvelopeMode::Exp,
-                            "Exp",
-                        );
+                        for mode in EnvelopeMode::ALL {
+                            ui.selectable_value(
+                                &mut self.params.envelope_mode,
+                                mode,
+                                format!("{mode:?}"),
+                            );
+                        }
                     });
+                if envelope_mode != self.params.envelope_mode {
+                    self.update_params();
+                }
 
                 let mut response_list = vec![];
 
-                match &self.params.envelope_mode {
-                    EnvelopeMode::Smooth => {}
-                    EnvelopeMode::Exp => {
-                        let sharpness_slider =
-                            Slider::new(&mut self.params.envelope_sharpness, 0.0..=1.00)
-                                .drag_value_speed(0.01)
-                                .text("Sharpness")
-                                .ui(ui);
+                if self.params.envelope_mode == EnvelopeMode::Exp {
+                    let sharpness_slider =
+                        Slider::new(&mut self.params.envelope_sharpness, 0.0..=1.00)
+                            .drag_value_speed(0.01)
+                            .text("Sharpness")
+                            .ui(ui);
+                    response_list.push(sharpness_slider);
+                }
 
-                        let shape_slider =
-                            Slider::new(&mut self.params.envelope_shape, 0.01..=0.99)
-                                .drag_value_speed(0.01)
-                                .text("Shape")
-                                .ui(ui);
+                // Smooth and Custom windows have no shape, the rest each use it differently
+                if let Some(name) = self.params.envelope_mode.shape_name() {
+                    let shape_slider = Slider::new(&mut self.params.envelope_shape, 0.01..=0.99)
+                        .drag_value_speed(0.01)
+                        .text(name)
+                        .ui(ui);
+                    response_list.push(shape_slider);
+                }
 
-                        // When the sliders are present, push the results so changes are listened for
-                        response_list.push(sharpness_slider);
-                        response_list.push(shape_slider);
-                    }
+                if self.params.envelope_mode == EnvelopeMode::Custom
+                    && self
+                        .envelope_editor
+                        .ui(ui, &mut self.params.envelope_points)
+                {
+                    self.update_params();
                 }
 
+                // When the sliders are present, push the results so changes are listened for
                 call_on_change(|| self.update_params(), &response_list)
             })
         });
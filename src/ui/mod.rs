@@ -1,6 +1,8 @@
 pub mod delay_ui;
+pub mod envelope_ui;
 pub mod grain_ui;
 pub mod plant_ui;
+pub mod synth_ui;
 
 pub use delay_ui::DelayUi;
 use egui::Response;
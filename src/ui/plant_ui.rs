@@ -1,4 +1,5 @@
-use crate::lsystem::{LSystem, Turtle};
+use crate::granular::sequencer::Leaf;
+use crate::lsystem::Turtle;
 use crate::plant::Plant;
 use eframe::emath::{pos2, Pos2, Rect, RectTransform, Vec2};
 use eframe::epaint::{Color32, Shape, Stroke};
@@ -30,18 +31,19 @@ pub struct LSystemUi {
     pub leaf_bias: f32,
     pub leaf_width: f32,
     pub leaf_rand: f32,
-    sender: Sender<Vec<Pos2>>,
+    sender: Sender<Vec<Leaf>>,
 }
 
 #[derive(Default)]
 struct PlantData {
     pub shapes: Vec<Vec<Shape>>,
+    #[allow(dead_code)]
     pub branch_points: Vec<Pos2>,
-    pub leaf_points: Vec<Pos2>,
+    pub leaf_points: Vec<Leaf>,
 }
 
 impl LSystemUi {
-    pub fn new(sender: Sender<Vec<Pos2>>) -> Self {
+    pub fn new(sender: Sender<Vec<Leaf>>) -> Self {
         Self {
             canvas_size: 500.0,
             plants: vec![
@@ -122,7 +124,7 @@ impl LSystemUi {
                         match c {
                             'l' => {
                                 let pos = transform * self.map_coord(turtle.get().0);
-                                let leaf = self.leaf(
+                                let (leaf, colour) = self.leaf(
                                     pos,
                                     self.leaf_length,
                                     self.leaf_bias,
@@ -131,7 +133,11 @@ impl LSystemUi {
                                     &mut rng,
                                 );
                                 shapes.push(vec![leaf]);
-                                leaf_points.push(self.map_coord(turtle.get().0))
+                                leaf_points.push(Leaf {
+                                    pos: self.map_coord(turtle.get().0),
+                                    colour,
+                                    depth: turtle.depth(),
+                                })
                             }
                             'x' => {}
                             'f' => {
@@ -230,6 +236,7 @@ impl LSystemUi {
         pos2(p.x + self.canvas_size / 2.0, self.canvas_size - p.y)
     }
 
+    // Returns the leaf shape and the index of its colour
     fn leaf(
         &self,
         pos: Pos2,
@@ -238,7 +245,7 @@ impl LSystemUi {
         width: f32,
         angle: f32,
         rng: &mut Mcg128Xsl64,
-    ) -> Shape {
+    ) -> (Shape, usize) {
         const NUM_POINTS: usize = 12;
         let len_rand = self.leaf_rand * len * 0.7 * (rng.random::<f32>() - 0.5);
         let width_rand = self.leaf_rand * width * 0.5 * (rng.random::<f32>() - 0.5);
@@ -290,16 +297,17 @@ impl LSystemUi {
             .map(|p| rotate_point(*p))
             .collect::<Vec<Pos2>>();
 
-        let base_colour = self
-            .plant()
-            .leaf_colours
-            .choose(rng)
-            .expect("Colour Slice is empty!");
+        let leaf_colours = &self.plant().leaf_colours;
+        let base_colour = leaf_colours.choose(rng).expect("Colour Slice is empty!");
+        let colour_index = leaf_colours
+            .iter()
+            .position(|c| std::ptr::eq(c, base_colour))
+            .unwrap_or(0);
 
         let colour = base_colour.gamma_multiply_u8(120 + colour_rand as u8);
         let mut leaf = Shape::convex_polygon(rotated, colour, Stroke::NONE);
         leaf.translate(Vec2::new(pos.x, pos.y));
-        leaf
+        (leaf, colour_index)
     }
 
     pub fn plant_window(&mut self, ui: &mut Ui) -> Response {
@@ -327,7 +335,7 @@ impl LSystemUi {
 
         let mut points = self.plant_data.leaf_points.clone();
         // Sort by y coordinate of leaves
-        points.sort_by(|p1, p2| p1.y.partial_cmp(&p2.y).unwrap_or(Ordering::Equal));
+        points.sort_by(|p1, p2| p1.pos.y.partial_cmp(&p2.pos.y).unwrap_or(Ordering::Equal));
         // let points = self.plant_data.branch_points.clone()
 
         self.sender
@@ -0,0 +1,60 @@
+use crate::granular::synth::{SynthParams, Waveform};
+use egui::{ComboBox, Slider, TextEdit, Ui, Widget};
+use std::path::PathBuf;
+
+/// Parameter panel for a generated slot. Returns true when the buffer should be generated.
+pub fn synth_panel(
+    ui: &mut Ui,
+    slot: usize,
+    params: &mut SynthParams,
+    wavetable: &mut String,
+) -> bool {
+    ui.horizontal(|ui| {
+        // Every slot has a panel, so the id needs the slot to be unique
+        ComboBox::from_id_salt(("Waveform", slot))
+            .selected_text(format!("{:?}", params.waveform))
+            .show_ui(ui, |ui| {
+                for waveform in Waveform::ALL {
+                    ui.selectable_value(&mut params.waveform, waveform, format!("{waveform:?}"));
+                }
+            });
+
+        if params.waveform != Waveform::Noise {
+            Slider::new(&mut params.frequency, 20.0..=2000.0)
+                .logarithmic(true)
+                .text("Frequency")
+                .suffix(" Hz")
+                .ui(ui);
+        }
+        Slider::new(&mut params.length, 0.1..=10.0)
+            .text("Length")
+            .suffix(" s")
+            .ui(ui);
+
+        match params.waveform {
+            Waveform::Fm => {
+                Slider::new(&mut params.fm_ratio, 0.25..=8.0)
+                    .drag_value_speed(0.01)
+                    .text("Ratio")
+                    .ui(ui);
+                Slider::new(&mut params.fm_index, 0.0..=10.0)
+                    .drag_value_speed(0.01)
+                    .text("Index")
+                    .ui(ui);
+            }
+            Waveform::Wavetable => {
+                TextEdit::singleline(wavetable)
+                    .hint_text("Single cycle path")
+                    .ui(ui);
+            }
+            _ => {}
+        }
+
+        let generate = ui.button("Generate").clicked();
+        if generate {
+            params.wavetable = PathBuf::from(&*wavetable);
+        }
+        generate
+    })
+    .inner
+}